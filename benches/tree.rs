@@ -0,0 +1,95 @@
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use red_black_tree::Tree;
+
+const N: u32 = 100_000;
+const ROUNDS: u32 = 5;
+
+// Runs f on a fresh input ROUNDS times and reports the fastest run, which is the least disturbed
+// by whatever else the machine is doing. Only f itself is timed, not building its input or
+// dropping what it returns
+fn bench<I, O, S: Fn() -> I, F: Fn(I) -> O>(name: &str, setup: S, f: F) -> Duration {
+    let mut best = Duration::MAX;
+    for _ in 0..ROUNDS {
+        let input = setup();
+        let start = Instant::now();
+        let output = black_box(f(input));
+        best = best.min(start.elapsed());
+        drop(output);
+    }
+    println!("{:<44} {:>10.2?}", name, best);
+    best
+}
+
+fn full_tree() -> Tree<u32> {
+    (0..N).collect()
+}
+
+// Even values in the tree and the odd values between them as a sorted batch, so every element of
+// the batch lands between two existing ones
+fn tree_and_batch(batch: u32) -> (Tree<u32>, Vec<u32>) {
+    ((0..N).map(|i| i * 2).collect(), (0..batch).map(|i| i * 2 + 1).collect())
+}
+
+// Keys sharing a long prefix, so every comparison has to scan past it
+fn string_tree() -> Tree<String> {
+    (0..N).map(|i| format!("{:>64}{:08}", "", i)).collect()
+}
+
+fn main() {
+    println!("{} elements, best of {} runs", N, ROUNDS);
+
+    // Emptying a tree from one end, the way a priority queue is used. With u32 the comparisons
+    // are nearly free and the rebalancing dominates, the String keys show what skipping them saves
+    bench("remove_min until empty", full_tree, |mut tree| {
+        while tree.remove_min() {}
+        tree
+    });
+    bench("remove(first().clone()) until empty", full_tree, |mut tree| {
+        while let Some(min) = tree.first().cloned() {
+            black_box(tree.remove(&min));
+        }
+    });
+    bench("remove_min until empty, String keys", string_tree, |mut tree| {
+        while tree.remove_min() {}
+        tree
+    });
+    bench("remove(first().clone()) until empty, String", string_tree, |mut tree| {
+        while let Some(min) = tree.first().cloned() {
+            black_box(tree.remove(&min));
+        }
+    });
+    bench("remove_max until empty", full_tree, |mut tree| {
+        while tree.remove_max() {}
+        tree
+    });
+    bench("remove(last().clone()) until empty", full_tree, |mut tree| {
+        while let Some(max) = tree.last().cloned() {
+            black_box(tree.remove(&max));
+        }
+    });
+
+    // Adding a sorted batch as large as the tree, where merging and rebuilding beats inserting
+    // each element, and a small batch, which extend_from_sorted inserts one at a time as well
+    bench("extend_from_sorted, batch of N", || tree_and_batch(N), |(mut tree, batch)| {
+        tree.extend_from_sorted(batch);
+        tree
+    });
+    bench("insert each, batch of N", || tree_and_batch(N), |(mut tree, batch)| {
+        for value in batch {
+            tree.insert(value);
+        }
+        tree
+    });
+    bench("extend_from_sorted, batch of N / 100", || tree_and_batch(N / 100), |(mut tree, batch)| {
+        tree.extend_from_sorted(batch);
+        tree
+    });
+    bench("insert each, batch of N / 100", || tree_and_batch(N / 100), |(mut tree, batch)| {
+        for value in batch {
+            tree.insert(value);
+        }
+        tree
+    });
+}
@@ -0,0 +1,117 @@
+use std::borrow::Borrow;
+use std::sync::RwLock;
+
+use crate::rbt::Tree;
+
+/// A tree that can be shared between threads. Reads take a shared lock so any number of threads
+/// can search the tree at once, while inserts and removes take the lock exclusively.
+/// Every method panics if the lock was poisoned by a thread that panicked while holding it.
+pub struct ConcurrentTree<T> {
+    tree: RwLock<Tree<T>>,
+}
+
+impl<T: std::cmp::PartialOrd> ConcurrentTree<T> {
+    /// Creates a new, empty concurrent tree
+    pub fn new() -> ConcurrentTree<T> {
+        ConcurrentTree { tree: RwLock::new(Tree::new()) }
+    }
+
+    /// Inserts data into the tree, blocking until no other thread is reading or writing.
+    /// Returns false if an equal element was already in the tree
+    pub fn insert(&self, input: T) -> bool {
+        self.tree.write().unwrap().insert(input)
+    }
+
+    /// Removes an element from the tree if it is present and returns it, blocking until no other
+    /// thread is reading or writing
+    pub fn remove<Q: std::cmp::PartialOrd + ?Sized>(&self, elem: &Q) -> Option<T>
+    where
+        T: Borrow<Q>,
+    {
+        self.tree.write().unwrap().remove(elem)
+    }
+
+    /// Searches the tree for a given value. Only waits on writers, other readers run alongside
+    pub fn contains<Q: std::cmp::PartialOrd + ?Sized>(&self, value: &Q) -> bool
+    where
+        T: Borrow<Q>,
+    {
+        self.tree.read().unwrap().contains(value)
+    }
+
+    /// Takes a snapshot of the elements in sorted order while holding the read lock
+    pub fn read_sorted(&self) -> Vec<T>
+    where
+        T: Clone,
+    {
+        self.tree.read().unwrap().iter().cloned().collect()
+    }
+}
+
+impl<T: std::cmp::PartialOrd> Default for ConcurrentTree<T> {
+    fn default() -> ConcurrentTree<T> {
+        ConcurrentTree::new()
+    }
+}
+
+impl<T> From<Tree<T>> for ConcurrentTree<T> {
+    fn from(tree: Tree<T>) -> ConcurrentTree<T> {
+        ConcurrentTree { tree: RwLock::new(tree) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn readers_and_writers_share_the_tree() {
+        let tree = Arc::new(ConcurrentTree::new());
+        let writers: Vec<_> = (0..4)
+            .map(|w| {
+                let tree = Arc::clone(&tree);
+                thread::spawn(move || {
+                    for i in 0..250 {
+                        assert!(tree.insert(w * 250 + i));
+                    }
+                })
+            })
+            .collect();
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let tree = Arc::clone(&tree);
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        // Whatever the writers have done so far, a snapshot is always sorted
+                        let snapshot = tree.read_sorted();
+                        assert!(snapshot.windows(2).all(|w| w[0] < w[1]));
+                    }
+                })
+            })
+            .collect();
+        for handle in writers.into_iter().chain(readers) {
+            handle.join().unwrap();
+        }
+        assert_eq!(tree.read_sorted(), (0..1000).collect::<Vec<_>>());
+
+        let removers: Vec<_> = (0..4)
+            .map(|w| {
+                let tree = Arc::clone(&tree);
+                thread::spawn(move || {
+                    for i in (w..1000).step_by(4) {
+                        if i % 2 == 0 {
+                            assert_eq!(tree.remove(&i), Some(i));
+                        }
+                        assert_eq!(tree.contains(&i), i % 2 == 1);
+                    }
+                })
+            })
+            .collect();
+        for handle in removers {
+            handle.join().unwrap();
+        }
+        assert_eq!(tree.read_sorted(), (1..1000).step_by(2).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,13 @@
+pub mod rbt;
+pub mod rbt_map;
+pub mod snapshot;
+
+#[cfg(feature = "concurrent")]
+pub mod concurrent;
+
+#[cfg(feature = "serde")]
+mod serde_impl;
+
+pub use rbt::{Color, Cursor, CursorMut, Difference, Drain, ExtractIf, Intersection, IntoIter, Iter, LevelOrder, Merge, MergeItem, NodeInfo, NodePosition, Op, PostOrder, PreOrder, Range, SymmetricDifference, TraversalOrder, Traverse, Tree, TreeStats, Union};
+pub use rbt_map::{Entry, TreeMap};
+pub use snapshot::SnapshotValue;
@@ -1,4 +1,19 @@
-use std::collections::LinkedList;
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::collections::{BTreeSet, LinkedList, VecDeque};
+use std::io::{self, Read, Write};
+use std::iter::{FusedIterator, Peekable};
+use std::marker::PhantomData;
+use std::ops::{BitAnd, BitOr, BitXor, Bound, ControlFlow, Index, RangeBounds, Sub};
+
+use crate::snapshot::{self, SnapshotValue};
+
+// Set operations on two trees switch from walking both side by side to looking up the elements of
+// the smaller one in the larger one once the larger is more than this many times the size. Sorted
+// batches use the same cut off to decide between inserting one by one and rebuilding
+const LOPSIDED_RATIO: usize = 16;
 
 /// Red-Black trees are a type of self balancing binary search tree
 /// balancing the tree preserves the worst case complexity of the
@@ -9,6 +24,9 @@ use std::collections::LinkedList;
 /// vector reaches capacity it will be resized. Thus if
 /// you need to continuously add nodes to the tree at runtime
 /// with no known upper bound this implementation is not recommended
+///
+/// A tree only owns its data through vectors, a linked list and boxes, so `Tree<T>` is `Send`
+/// when `T` is `Send` and `Sync` when `T` is `Sync`.
 /*
  * The tree structure contains for pieces of information:
  *      Nodes: A vector filled with the Nodes
@@ -24,6 +42,11 @@ use std::collections::LinkedList;
  *      color: a vector of boolean values to denote the color (red or black) of the node
  *          true: red;
  *          false: black
+ *
+ *      subtree_size: the number of nodes in the subtree rooted at each node (including itself).
+ *      This lets order statistic queries (counting elements in a range) run in O(log n)
+ *
+ *      len: the number of elements in the tree
  */
 pub struct Tree<T> {
    graph: Vec<Node<T>> ,
@@ -31,193 +54,441 @@ pub struct Tree<T> {
    empty: LinkedList<usize>, // Linked list so that we don't have to worry about allocation
     // Linked list has a is_empty function. That will be useful for insertions
     color: Vec<bool>,
+    subtree_size: Vec<usize>,
     root: Option<usize>, // index with the root of the tree
+    len: usize,
+}
+
+/// The color of a node in the tree. Internally this is stored as a bool (true: red),
+/// this enum is what gets handed out through the public API
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    Red,
+    Black,
+}
+
+impl From<bool> for Color {
+    fn from(is_red: bool) -> Color {
+        if is_red {
+            Color::Red
+        } else {
+            Color::Black
+        }
+    }
+}
+
+/// The order in which `Tree::traverse` visits the elements
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraversalOrder {
+    /// Left branch, node, right branch. This is sorted order
+    In,
+    /// Node, left branch, right branch
+    Pre,
+    /// Left branch, right branch, node
+    Post,
+    /// Breadth first, from the root down one level at a time
+    Level,
+}
+
+/// Metrics describing the health of a tree, gathered in one pass by `Tree::stats`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TreeStats {
+    /// Number of elements in the tree
+    pub len: usize,
+    /// Number of nodes on the longest path from the root to a leaf
+    pub height: usize,
+    /// Number of black nodes on every path from the root to a leaf. None means the red-black
+    /// properties are violated (the paths disagree or a red node has a red child)
+    pub black_height: Option<usize>,
+    /// Number of red nodes
+    pub red_nodes: usize,
+    /// Number of slots freed by removals that are waiting to be reused
+    pub free_slots: usize,
+}
+
+/// Where a node hangs in the tree, see `NodeInfo`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodePosition {
+    Root,
+    LeftChild,
+    RightChild,
+}
+
+/// Everything `Tree::iter_annotated` knows about a node, enough to draw the tree or check its
+/// invariants from the outside without exposing the indices it is stored at
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NodeInfo<'a, T> {
+    pub value: &'a T,
+    /// Number of edges between the root and the node, the root is at depth 0
+    pub depth: usize,
+    pub color: Color,
+    pub position: NodePosition,
+}
+
+/// A single operation on a tree. A recorded sequence of these can be replayed with
+/// `Tree::from_operations` to reproduce a bug deterministically
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op<T> {
+    Insert(T),
+    Remove(T),
 }
-// Nodes simply contain the data
+
+// Nodes simply contain the data. Data is None while the node's index is on the empty stack,
+// so a removed value is dropped straight away instead of when its slot gets reused
+#[derive(Clone)]
 struct Node<T> {
-    data: Box<T>, 
+    data: Option<Box<T>>,
 }
 
-impl<T: std::cmp::PartialOrd> Tree<T>  {
-    /// Function to create a new Red-Black Tree. Returns an empty tree
-    pub fn new() -> Tree<T> {
-        Tree { graph: Vec::new(), edge_list: Vec::new(), empty: LinkedList::new(), color: Vec::new(), root: None}
+impl<T> Tree<T> {
+    /// Function to create a new Red-Black Tree. Returns an empty tree. Nothing is allocated
+    /// until the first insert, so this is a const fn and can initialise a static
+    pub const fn new() -> Tree<T> {
+        Tree { graph: Vec::new(), edge_list: Vec::new(), empty: LinkedList::new(), color: Vec::new(), subtree_size: Vec::new(), root: None, len: 0}
     }
+}
 
+impl<T: std::cmp::PartialOrd> Tree<T>  {
     /// With capacity function creates a new tree with the specified vector capacity.
     /// If the upper bound on the number of nodes you will need is known it is strongly
     /// recommended that you use this method to avoid potential O(n) resizing of vectors
     pub fn with_capacity(size: usize) -> Tree<T> {
-        Tree { graph: Vec::with_capacity(size), edge_list: Vec::with_capacity(size), empty: LinkedList::new(), color: Vec::with_capacity(size), root: None }
-    }
-
-    /// Insert does exactly what it says, it inserts data into the tree, rebalancing if necessary
-    pub fn insert (&mut self, input: T){
-        let in_pnt: Option<usize>;
-        if self.root.is_none() { // Tree is empty, add the root
-            if !self.empty.is_empty() { // Empty stack has some value, reuse these indicies
-                let opt_root = self.empty.pop_back(); // Get root value off the stack. This is popped as an Option
-                let root_unwrapped = opt_root.unwrap();
-                self.root = opt_root;
-                self.edge_list[root_unwrapped] = vec![None, None, None];
-                self.graph[root_unwrapped] = Node {data: Box::new(input)};
-                self.color[root_unwrapped] = false;
-            } else {
-                self.graph.push(Node { data: Box::new(input)});
-                self.edge_list.push(vec![None, None, None]); // The root has no parents, and no siblings. How sad :(
-                self.root = Some(0);
-                self.color.push(false);
-            }
-        } else{ // The tree has nodes
-            let is_empty: bool;
-            (in_pnt, is_empty) =  self.insert_helper(&input, self.root); // recursively insert
-            match in_pnt {
-                Some(i) => {
-                    // Data inserted, rebalance
-                    if is_empty {
-                        self.graph.push(Node { data: Box::new(input) });
-                    } else {
-                        self.graph[i].data = Box::new(input);
-                    }
-                    self.insert_rebalance(i); // recolor and rebalance the tree if necessary
+        Tree { graph: Vec::with_capacity(size), edge_list: Vec::with_capacity(size), empty: LinkedList::new(), color: Vec::with_capacity(size), subtree_size: Vec::with_capacity(size), root: None, len: 0 }
+    }
+
+    /// Creates a tree with size empty slots already allocated and marked free, so the first size
+    /// inserts reuse slots instead of growing the arena vectors. Each insert still boxes its value,
+    /// so this saves the resizing, not every allocation. Unlike `with_capacity`, which only
+    /// reserves space, this pays for every slot (and its edges) up front even if the tree
+    /// never fills up, so only use it when the bound is tight
+    pub fn preallocated(size: usize) -> Tree<T> {
+        let mut tree: Tree<T> = Tree::with_capacity(size);
+        tree.graph.resize_with(size, || Node { data: None });
+        tree.edge_list.resize(size, vec![None, None, None]);
+        tree.color.resize(size, false);
+        tree.subtree_size.resize(size, 0);
+        tree.empty.extend((0..size).rev()); // popped from the back, so index 0 is used first
+        tree
+    }
+
+    /// Returns the number of elements in the tree
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the tree holds no elements
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Insert does exactly what it says, it inserts data into the tree, rebalancing if necessary.
+    /// Returns true if input was inserted and false if an equal element was already in the tree,
+    /// in which case input is dropped
+    pub fn insert (&mut self, input: T) -> bool {
+        match self.find_slot(self.root, &input) {
+            Ok(_) => {
+                false // Element is already in the tree, nothing is inserted
+            }
+            Err((parent, is_left)) => {
+                self.insert_at(parent, is_left, input);
+                true
+            }
+        }
+    }
+
+    /// Inserts value, overwriting an equal element if one is already stored. The old element is
+    /// returned, or None if value was newly inserted. Overwriting doesn't change the shape of
+    /// the tree, so no rebalancing is done in that case
+    pub fn replace(&mut self, value: T) -> Option<T> {
+        match self.find_slot(self.root, &value) {
+            Ok(idx) => {
+                match self.graph[idx].data.as_deref_mut() {
+                    Some(d) => Some(std::mem::replace(d, value)),
+                    None => unreachable!(), // find_slot only returns linked nodes
                 }
-                None =>{
-                    // Nothing was inserted.
+            }
+            Err((parent, is_left)) => {
+                self.insert_at(parent, is_left, value);
+                None
+            }
+        }
+    }
+
+    /// Inserts value unless an equal element is already stored, in which case value is handed
+    /// back untouched as `Err(value)`. The duplicate is found before any slot is allocated,
+    /// so a failed insert leaves the tree exactly as it was
+    pub fn try_insert(&mut self, value: T) -> Result<(), T> {
+        match self.find_slot(self.root, &value) {
+            Ok(_) => Err(value),
+            Err((parent, is_left)) => {
+                self.insert_at(parent, is_left, value);
+                Ok(())
+            }
+        }
+    }
+
+    /// Inserts data without ever growing the vectors. If there is no free slot left from a removal
+    /// and the vectors are at capacity, the value is handed back as `Err(value)` instead of
+    /// triggering an O(n) resize, so the tree can be treated as fixed capacity.
+    /// Returns `Ok(true)` if the value was inserted and `Ok(false)` if it was already in the tree
+    pub fn insert_within_capacity(&mut self, input: T) -> Result<bool, T> {
+        match self.find_slot(self.root, &input) {
+            Ok(_) => Ok(false),
+            Err(_) if self.is_full() => Err(input),
+            Err((parent, is_left)) => {
+                self.insert_at(parent, is_left, input);
+                Ok(true)
+            }
+        }
+    }
+
+    // True if inserting a new node would have to grow one of the vectors
+    fn is_full(&self) -> bool {
+        self.empty.is_empty()
+            && (self.graph.len() == self.graph.capacity()
+                || self.edge_list.len() == self.edge_list.capacity()
+                || self.color.len() == self.color.capacity()
+                || self.subtree_size.len() == self.subtree_size.capacity())
+    }
+
+    /// Inserts value using cmp to order it against the stored elements instead of `PartialOrd`,
+    /// e.g. to order values on one of their fields. Nothing is inserted if cmp finds an equal
+    /// element. Every operation on a given tree must order elements consistently with cmp,
+    /// mixing orderings leaves the tree in an unspecified order and lookups may miss elements
+    pub fn insert_by<F: Fn(&T, &T) -> Ordering>(&mut self, value: T, cmp: F) {
+        match self.find_slot_by(self.root, |d| cmp(d, &value)) {
+            Ok(_) => {
+                // An equal element is already in the tree, nothing is inserted
+            }
+            Err((parent, is_left)) => {
+                self.insert_at(parent, is_left, value);
+            }
+        }
+    }
+
+    /// Looks up like and returns a reference to the stored element. If it is not in the tree,
+    /// the element produced by f is inserted and a reference to it is returned instead.
+    /// The tree is only searched once and like is only borrowed, so it never has to be cloned.
+    /// f must produce an element equal to like, since it is placed where like would have been
+    /// inserted. Rebalancing after the insert only rewires edges and never moves an element to
+    /// another slot, so the slot f's element lands in is where it stays
+    pub fn get_or_insert_with<F: FnOnce() -> T>(&mut self, like: &T, f: F) -> &T {
+        let idx = match self.find_slot(self.root, like) {
+            Ok(idx) => idx,
+            Err((parent, is_left)) => {
+                let input = f();
+                debug_assert!(!matches!(input.partial_cmp(like), Some(Ordering::Less | Ordering::Greater)), "f must produce an element equal to like");
+                self.insert_at(parent, is_left, input)
+            }
+        };
+        self.value(idx)
+    }
+
+    /// Inserts every element of an ascending sequence. A batch that is small next to the tree is
+    /// inserted one element at a time, each search starting from where the previous element went
+    /// rather than from the root. A larger batch is merged with the elements already in the tree
+    /// in a single sorted walk and the tree rebuilt from the result, so it costs O(n + m) instead
+    /// of m separate inserts with their rotations. The rebuild reuses the tree's vectors, so
+    /// capacity from `with_capacity` is kept. Duplicates, within the batch or with the tree, are
+    /// dropped like `insert`. An out of order batch is caught by a debug assertion, in release
+    /// builds the elements that break the order are inserted one at a time instead, so none are lost
+    pub fn extend_from_sorted(&mut self, sorted: impl IntoIterator<Item = T>) {
+        let mut batch = sorted.into_iter();
+        // Only as much of the batch is pulled in as could still be inserted one by one
+        let small = self.len / LOPSIDED_RATIO;
+        let head: Vec<T> = batch.by_ref().take(small + 1).collect();
+        if head.len() <= small { // the whole batch fit, an empty batch doesn't rebuild either
+            self.insert_sorted(head);
+            return;
+        }
+        let capacity = self.len + head.len() + batch.size_hint().0;
+        let (merged, strays) = merge_sorted(self.drain(), head.into_iter().chain(batch), capacity);
+        self.fill_sorted(merged);
+        for input in strays {
+            self.insert(input);
+        }
+    }
+
+    // Inserts an ascending sequence one element at a time. Each element is searched for starting
+    // from where the previous one was inserted, so a batch landing in one region of the tree skips
+    // most of the descent
+    fn insert_sorted(&mut self, sorted: Vec<T>) {
+        let mut hint: Option<usize> = None; // index of the previous element of the batch
+        for input in sorted {
+            let start = match hint {
+                Some(h) if input.partial_cmp(self.value(h)) != Some(Ordering::Less) => self.sorted_search_start(h, &input),
+                _ => {
+                    // Out of order input can't use the hint, it is searched for from the root
+                    debug_assert!(hint.is_none(), "extend_from_sorted input must be ascending");
+                    self.root
                 }
+            };
+            hint = match self.find_slot(start, &input) {
+                Ok(idx) => Some(idx), // Element is already in the tree
+                Err((parent, is_left)) => Some(self.insert_at(parent, is_left, input)),
+            };
         }
+    }
+
+    // Given the index of an element smaller than value, climbs to the lowest ancestor whose
+    // subtree must contain the position of value. Every ancestor's subtree already covers
+    // everything just above the hint, so we only need to find one whose upper end is past value
+    fn sorted_search_start(&self, hint: usize, value: &T) -> Option<usize> {
+        let mut index = hint;
+        while let Some(p) = self.edge_list[index][0] {
+            if self.edge_list[p][1] == Some(index) && value < self.value(p) {
+                break; // index is a left child and value is below its parent, it belongs under index
+            }
+            index = p;
         }
+        Some(index)
     }
 
-    // Private method to help recursively insert a node
-    fn insert_helper(&mut self, input: &T, index: Option<usize>) -> (Option<usize>, bool) {
-        let idx = index.unwrap();
-        let mut is_empty : bool = true;
-        let mut ret: Option<usize> = None;
-        if input < &self.graph[idx].data { // Move left
-            if self.edge_list[idx][1].is_some(){ // check that it's not None
-                (ret, is_empty) = self.insert_helper(input, self.edge_list[idx][1]);
-            } else { // insert
-                if self.empty.is_empty(){ // need to add a new element
-                    ret = Some(self.graph.len());
-                    self.edge_list[idx][1] = ret;
-                    self.edge_list.push(vec![index, None, None]);
-                    self.color.push(true);
-                    is_empty = true;
+    // Descends from start looking for value. Returns Ok(index) if an equal element is stored,
+    // otherwise Err((parent, is_left)) with the position where value belongs
+    fn find_slot(&self, start: Option<usize>, value: &T) -> Result<usize, (Option<usize>, bool)> {
+        self.find_slot_by(start, |d| {
+            if value < d {
+                Ordering::Greater
+            } else if value > d {
+                Ordering::Less
+            } else {
+                Ordering::Equal
+            }
+        })
+    }
 
-                } else {
-                    ret = self.empty.pop_back();
-                    self.edge_list[idx][1] = ret;
-                    match ret {
-                        Some(r) => {
-                            self.edge_list[r] = vec![index, None, None];
-                            self.color[r] = true;
-                            is_empty = false
-                        }
-                        None => {
-                            unreachable!();
-                        }
-                    }
+    // Same as find_slot, but for any borrowed form of T, so the map can search by key alone
+    pub(crate) fn find_slot_borrowed<Q: std::cmp::PartialOrd + ?Sized>(&self, value: &Q) -> Result<usize, (Option<usize>, bool)>
+    where
+        T: Borrow<Q>,
+    {
+        self.find_slot_by(self.root, |d| {
+            if value < d.borrow() {
+                Ordering::Greater
+            } else if value > d.borrow() {
+                Ordering::Less
+            } else {
+                Ordering::Equal
+            }
+        })
+    }
+
+    // Same as find_slot, but f says how each element compares to the value being looked for
+    fn find_slot_by<F: Fn(&T) -> Ordering>(&self, start: Option<usize>, f: F) -> Result<usize, (Option<usize>, bool)> {
+        let mut parent: Option<usize> = None;
+        let mut is_left = false;
+        let mut index = start;
+        while let Some(idx) = index {
+            match f(self.value(idx)) {
+                Ordering::Greater => { // Move left
+                    is_left = true;
+                    index = self.edge_list[idx][1];
+                }
+                Ordering::Less => { // Move right
+                    is_left = false;
+                    index = self.edge_list[idx][2];
+                }
+                Ordering::Equal => { // Element is already stored
+                    return Ok(idx);
                 }
             }
+            parent = Some(idx);
         }
-        if input > &self.graph[idx].data { // Move right
-            if self.edge_list[idx][2].is_some(){ // check that it's not None
-                (ret, is_empty) = self.insert_helper(input, self.edge_list[idx][2]);
-            } else { // insert
-                if self.empty.is_empty(){ // need to add a new element
-                    ret = Some(self.graph.len());
-                    self.edge_list[idx][2] = ret;
-                    self.edge_list.push(vec![index, None, None]);
-                    self.color.push(true);
-                    is_empty = true;
+        Err((parent, is_left))
+    }
+
+    // Attaches input as a new red node at the position found by find_slot and rebalances.
+    // Returns the index input is stored at. Rebalancing only rewires edges, so this index stays valid
+    pub(crate) fn insert_at(&mut self, parent: Option<usize>, is_left: bool, input: T) -> usize {
+        let idx = self.alloc_node(input);
+        self.edge_list[idx][0] = parent;
+        match parent {
+            Some(p) => {
+                if is_left {
+                    self.edge_list[p][1] = Some(idx);
                 } else {
-                    ret = self.empty.pop_back();
-                    self.edge_list[idx][2] = ret;
-                    match ret {
-                        Some(r) => {
-                            self.edge_list[r] = vec![index, None, None];
-                            self.color[r] = true;
-                            is_empty = false;
-                        }
-                        None => {
-                            unreachable!();
-                        }
-                    }
+                    self.edge_list[p][2] = Some(idx);
                 }
             }
+            None => {
+                self.root = Some(idx); // The root has no parents, and no siblings. How sad :(
+            }
         }
-        (ret, is_empty)
+        self.update_sizes(parent); // every ancestor gained a node
+        self.insert_rebalance(idx); // recolor and rebalance the tree if necessary
+        idx
     }
-    
-        // Private helper function to rebalance the tree after an insert
+
+    // Stores input in a free slot (or a new one at the end of the vectors) as a red node with no
+    // edges, and returns its index. The caller is responsible for linking it into the tree
+    fn alloc_node(&mut self, input: T) -> usize {
+        self.len += 1;
+        match self.empty.pop_back() {
+            Some(i) => { // Empty stack has some value, reuse these indicies
+                self.graph[i] = Node { data: Some(Box::new(input)) };
+                self.edge_list[i].fill(None); // keep the slot's edge vector rather than allocating a new one
+                self.color[i] = true;
+                self.subtree_size[i] = 1;
+                i
+            }
+            None => { // need to add a new element
+                self.graph.push(Node { data: Some(Box::new(input)) });
+                self.edge_list.push(vec![None, None, None]);
+                self.color.push(true);
+                self.subtree_size.push(1);
+                self.graph.len() - 1
+            }
+        }
+    }
+
+    // Private helper function to rebalance the tree after an insert. The node at index is red
+    // and may have a red parent
     fn insert_rebalance(&mut self, index: usize) {
-        //Recolor
         match self.edge_list[index][0] { // Check if parent is None
             Some(p) => {
+                if !self.color[p] { // Parent is black, nothing to fix
+                    return;
+                }
                 match self.edge_list[p][0] { // check grandfather
                     Some(g) => {
-                        if self.edge_list[g][1] == Some(p) { // Node's parent is the left branch
-                            match self.edge_list[g][2] {
-                                Some(u) => {
-                                    if self.color[u] { // Uncle is red
-                                        // Change uncle and parent to 
-                                        self.color[u] = false;
-                                        self.color[p] = false;
-                                        self.color[g] = true;
-                                        self.insert_rebalance(g);
-                                    } else {
-                                        if self.edge_list[p][1] == Some(index) { // LL case
-                                            self.left_left_rotation(p);
-                                        } else { // LR case
-                                            self.left_right_rotation(p);
-                                        }
-                                    }
-                                }
-                                None => {
-                                    // No uncle exists. All None branches are black
-                                    if self.edge_list[p][1] == Some(index) { // LL case
-                                        self.left_left_rotation(p);
-                                    } else { // LR case
-                                            self.left_right_rotation(p);
-                                    }
-                                }
-                            }
-                        } else { // Node's parent must be right branch
-                            match self.edge_list[g][2] {
-                                Some(u) => {
-                                    if self.color[u] { // Uncle is red
-                                        // Change uncle and parent to 
-                                        self.color[u] = false;
-                                        self.color[p] = false;
-                                        self.color[g] = true;
-                                        self.insert_rebalance(g);
-                                    }
-                                    else {
-                                        if self.edge_list[p][1] == Some(index) { // RL case
-                                            self.right_left_rotation(p);
-                                        } else { // RR case
-                                            self.right_right_rotation(p);
-                                        }
-                                    }
-                                }
-                                None => {
-                                    // No uncle exists. All None branches are black
-                                    if self.edge_list[p][1] == Some(index) { // RL case
-                                        self.right_left_rotation(p);
-                                    } else { // RR case
-                                        self.right_right_rotation(p);
-                                    }
+                        let parent_is_left = self.edge_list[g][1] == Some(p);
+                        let uncle = if parent_is_left { self.edge_list[g][2] } else { self.edge_list[g][1] };
+                        if self.is_red(uncle) { // Uncle is red
+                            // Change uncle and parent to black and move the red up to the grandfather
+                            self.color[uncle.unwrap()] = false;
+                            self.color[p] = false;
+                            self.color[g] = true;
+                            self.insert_rebalance(g);
+                        } else { // Uncle is black. All None branches are black
+                            let top: usize; // node that takes the grandfather's place
+                            if parent_is_left {
+                                if self.edge_list[p][1] == Some(index) { // LL case
+                                    self.left_left_rotation(p);
+                                    top = p;
+                                } else { // LR case
+                                    self.left_right_rotation(p);
+                                    top = index;
                                 }
+                            } else if self.edge_list[p][1] == Some(index) { // RL case
+                                self.right_left_rotation(p);
+                                top = index;
+                            } else { // RR case
+                                self.right_right_rotation(p);
+                                top = p;
                             }
+                            self.color[top] = false;
+                            self.color[g] = true;
                         }
                     }
                     None => {
-                        // Node's parent is the root thus the node has no uncle
+                        // Node's parent is the root. The root is always black so this is never reached
                     }
                 }
             }
             None => {
-                // Node is the root
+                // Node is the root, which is always black
+                self.color[index] = false;
             }
         }
     }
@@ -256,6 +527,9 @@ impl<T: std::cmp::PartialOrd> Tree<T>  {
                 self.edge_list[idx][0] = self.edge_list[p][0];
         // update parent of former parent
                 self.edge_list[p][0] = index;
+        // the former parent is now below idx, so its size must be recomputed first
+                self.update_size(p);
+                self.update_size(idx);
             }
             None => {
                 unreachable!();
@@ -297,6 +571,9 @@ impl<T: std::cmp::PartialOrd> Tree<T>  {
                 self.edge_list[idx][0] = self.edge_list[p][0];
         // update parent of former parent
                 self.edge_list[p][0] = index;
+        // the former parent is now below idx, so its size must be recomputed first
+                self.update_size(p);
+                self.update_size(idx);
             }
             None => {
                 unreachable!();
@@ -312,13 +589,8 @@ impl<T: std::cmp::PartialOrd> Tree<T>  {
                 self.edge_list[child][0] = self.edge_list[index][0]; // set child parent to grandfather
                 self.edge_list[index][0] = self.edge_list[index][2]; // set parent to right child
                 self.edge_list[index][2] = self.edge_list[child][1]; // left child must become right child of index
-                match self.edge_list[child][1] {
-                    Some(lc) => {
-                        self.edge_list[lc][0] = idx; // set node at index as parent of left child of child
-                    }
-                    None => {
-
-                    }
+                if let Some(lc) = self.edge_list[child][1] {
+                    self.edge_list[lc][0] = idx; // set node at index as parent of left child of child
                 }
                 match self.edge_list[child][0] { // place child as child of grandfather
                     Some(g) => {
@@ -334,6 +606,8 @@ impl<T: std::cmp::PartialOrd> Tree<T>  {
                     }
                 }
                 self.edge_list[child][1] = idx; // index becomes left child
+                self.update_size(index);
+                self.update_size(child);
                 self.left_left_rotation(child);
             }
             None => {
@@ -350,13 +624,8 @@ impl<T: std::cmp::PartialOrd> Tree<T>  {
                 self.edge_list[child][0] = self.edge_list[index][0]; // set child parent to grandfather
                 self.edge_list[index][0] = self.edge_list[index][1]; // set parent to right child
                 self.edge_list[index][1] = self.edge_list[child][2]; // right child must become left child of index
-                match self.edge_list[child][2] {
-                    Some(rc) => {
-                        self.edge_list[rc][0] = idx;
-                    }
-                    None => {
-
-                    }
+                if let Some(rc) = self.edge_list[child][2] {
+                    self.edge_list[rc][0] = idx;
                 }
                 match self.edge_list[child][0] { // Place child as child of grandfather
                     Some(g) => {
@@ -371,6 +640,8 @@ impl<T: std::cmp::PartialOrd> Tree<T>  {
                     }
                 }
                 self.edge_list[child][2] = idx; // index becomes left child
+                self.update_size(index);
+                self.update_size(child);
                 self.right_right_rotation(child);
             }
             None => {
@@ -379,384 +650,4874 @@ impl<T: std::cmp::PartialOrd> Tree<T>  {
         }
     }
 
-    /// Function to remove a given element from the tree. If the element is not in the tree,
-    /// nothing is done.
-    pub fn remove(&mut self, elem: T) {
-        self.remove_recursive(self.root, &elem);
+    /// Function to remove a given element from the tree. Returns the stored element, or None
+    /// if it was not in the tree. Like `contains`, elem can be any borrowed form of T
+    pub fn remove<Q: std::cmp::PartialOrd + ?Sized>(&mut self, elem: &Q) -> Option<T>
+    where
+        T: Borrow<Q>,
+    {
+        self.contains_recursive(self.root, elem).map(|idx| self.remove_index(idx))
     }
 
-    fn remove_recursive(&mut self, start: Option<usize>, elem: &T) {
-        let index : Option<usize>;
-        let in_order_successor: Option<usize>;
-        if start == self.root { // Check if we were passed root. This avoids the overhead of a function call to search for the index if we already have it
-            index = self.contains_recursive(start, &elem); // find the index of the element to be removed
-        } else {
-            index = start; // index was known
+    /// Removes every key in keys that is in the tree and returns how many elements were removed.
+    /// Each key is removed on its own, so the tree is valid after every step, and a key missing
+    /// from the tree or repeated in the batch is simply not counted
+    pub fn remove_all<'a, I: IntoIterator<Item = &'a T>>(&mut self, keys: I) -> usize
+    where
+        T: 'a,
+    {
+        let mut removed: usize = 0;
+        for key in keys {
+            if let Some(idx) = self.contains_recursive(self.root, key) {
+                self.remove_index(idx); // the removed value is dropped here
+                removed += 1;
+            }
         }
-        match index {
-            Some(idx) => {
-                let lcn = self.edge_list[idx][1].is_none(); // left child None
-                let rcn = self.edge_list[idx][2].is_none(); // right child None
-                if lcn && rcn { // both children are None, element to be deleted is leaf
-                    match self.edge_list[idx][0] {
-                        Some(p) => {
-                            if  self.edge_list[p][1] == index {
-                                self.edge_list[p][1] = None;
-                            } else {
-                                self.edge_list[p][2] = None;
-                            }
-                            if !self.color[idx] {
-                                self.remove_helper(index);
-                            }
-                        }
-                        None => { // element to be removed is the root and the only element in the tree
-                            self.root = None;
-                        }
-                    }
-                    self.empty.push_back(idx); // Mark index as free in the stack
-                } else if lcn  && !rcn { // Left child is None, right child exists
-                    let rc_idx = self.edge_list[idx][2].unwrap();
-                    match self.edge_list[idx][0] {
-                        Some(p) => {
-                            if  self.edge_list[p][1] == index {
-                                self.edge_list[p][1] = self.edge_list[idx][2];
-                            } else {
-                                self.edge_list[p][2] = self.edge_list[idx][2];
-                            }
-                            if !self.color[idx] && !self.color[rc_idx]{
-                                self.remove_helper(index);
-                            }
-                        }
-                        None => {
-                            self.root = self.edge_list[idx][2];
-                            self.color[rc_idx] = false;
-                        }
-                    }
-                    self.empty.push_back(idx);
-                    let rc_idx = self.edge_list[idx][2].unwrap();
-                    self.edge_list[rc_idx][0] = self.edge_list[idx][0];
-                    if self.color[idx] || self.color[rc_idx] { // One of the two nodes are red
-                        self.color[rc_idx] = false; // paint child black
-                    }
-                } else if rcn && !lcn { // right child is None, left child exists
-                    let lc_idx = self.edge_list[idx][1].unwrap();
-                    match self.edge_list[idx][0] {
-                        Some(p) => {
-                            if  self.edge_list[p][1] == index {
-                                self.edge_list[p][1] = self.edge_list[idx][1];
-                            } else {
-                                self.edge_list[p][2] = self.edge_list[idx][1];
-                            }
-                            if !self.color[idx] && !self.color[lc_idx]{
-                                self.remove_helper(index);
-                            }
-                        }
-                        None => {
-                            self.root = self.edge_list[idx][1];
-                            self.color[lc_idx] = false;
-                        }
-                    }
-                    self.empty.push_back(idx);
-                    self.edge_list[lc_idx][0] = self.edge_list[idx][0];
-                    if self.color[idx] || self.color[lc_idx] { // One of the two nodes are red
-                        self.color[lc_idx] = false; // paint child black
-                    }
-                } else { // Both children exist
-                    // find in order successor
-                    let ios = self.get_in_order_successor(self.edge_list[idx][2].unwrap()); // The in order successor must be in the right branch
-                    let ios_c = self.color[ios];
-                    in_order_successor = Some(ios);
-                    match self.edge_list[idx][0] {
-                        Some(p) => { // Change child of index's parent to index's in order successor
-                            if self.edge_list[p][1] == index { // Check left child
-                                self.edge_list[p][1] = in_order_successor;
-                            } else { // else right child
-                                self.edge_list[p][2] = in_order_successor;
-                            }
-                        }
-                        None => {
-                            self.root = in_order_successor;
-                        }
-                    }
-                    let tmp_list = vec![self.edge_list[ios][0], self.edge_list[ios][1], self.edge_list[ios][2]]; // temp vector for swapping indices
-                    if self.edge_list[idx][2] == in_order_successor { // In order successor will never be in the left branch, check if it is a child node
-                        self.edge_list[ios] = vec![self.edge_list[idx][0], self.edge_list[idx][1], index]; // place index as the child instead
-                        self.edge_list[idx] = vec![in_order_successor, tmp_list[1], tmp_list[2]];
-                    } else {
-                        self.edge_list[ios] = vec![self.edge_list[idx][0], self.edge_list[idx][1], self.edge_list[idx][2]];
-                        self.edge_list[idx] = vec![tmp_list[0], tmp_list[1], tmp_list[2]];
-                        match tmp_list[0] {
-                            Some(ios_p) => {
-                                if self.edge_list[ios_p][1] == in_order_successor{
-                                    self.edge_list[ios_p][1] = index;
-                                } else {
-                                    self.edge_list[ios_p][2] = index;
-                                }
-                            }
-                            None => {
-                                unreachable!();
-                            }
-                        }
-                    }
-                    match self.edge_list[ios][1] {
-                        Some(lc) => {
-                            self.edge_list[lc][0] = in_order_successor;
-                        }
-                        None => {
-                            
-                        }
-                    }
-                    match self.edge_list[ios][2] {
-                        Some(rc) => {
-                            self.edge_list[rc][0] = in_order_successor;
-                        }
-                        None => {
-                            
-                        }
-                    }
-                    self.color[ios] = self.color[idx];
-                    self.color[idx] = ios_c;
-                    self.remove_recursive(index, elem);
-                }
+        removed
+    }
+
+    /// Appends other to this tree when every element of other is larger than every element of
+    /// this tree. This is a linear append, not the O(log n) join of a pointer-based tree: the
+    /// two trees keep their nodes in separate vectors, so the smaller one is moved node by node
+    /// into the vectors of the larger, which is O(min(n, m)). The moved nodes keep their shape,
+    /// and the trees are linked at a single node on the edge of the taller one whose black height
+    /// matches the shorter one, so no comparisons are made and only that node is rebalanced
+    pub fn join_disjoint(&mut self, other: Tree<T>) {
+        if other.root.is_none() {
+            return;
+        }
+        if self.root.is_none() {
+            *self = other;
+            return;
+        }
+        debug_assert!(self.last() < other.first(), "join_disjoint requires every element of other to be larger");
+        let mut other = other;
+        // self ends up holding the larger tree, which stays where it is
+        let swapped = other.len > self.len;
+        if swapped {
+            std::mem::swap(self, &mut other);
+        }
+        // The element on the side where the trees meet becomes the node joining them: the
+        // largest of the low tree, or the smallest of the high tree if self now holds that one
+        let r = self.root.unwrap();
+        let end = if swapped { self.get_in_order_successor(r) } else { self.get_in_order_predecessor(r) };
+        let junction = self.remove_index(end);
+        let adopted = self.adopt(&mut other);
+        let (left, right) = if swapped { (adopted, self.root) } else { (self.root, adopted) };
+        let left_height = self.spine_black_height(left);
+        let right_height = self.spine_black_height(right);
+        let idx = self.alloc_node(junction);
+        // Walk down the inner edge of the taller tree to a black node (or None) with the same black
+        // height as the shorter tree. The junction takes its place with it and the shorter tree as
+        // children, which keeps every path's black count equal
+        let taller_is_left = left_height >= right_height;
+        let dir = if taller_is_left { 2 } else { 1 };
+        let target = if taller_is_left { right_height } else { left_height };
+        let mut height = if taller_is_left { left_height } else { right_height };
+        let mut parent: Option<usize> = None;
+        let mut x = if taller_is_left { left } else { right };
+        self.root = x; // the shorter tree ends up under the root of the taller one
+        while height > target || self.is_red(x) {
+            let i = match x {
+                Some(i) => i,
+                None => unreachable!(), // the black height only reaches zero past the last black node
+            };
+            if !self.color[i] {
+                height -= 1;
             }
-            None => {
-                // Element is not in tree, no need to remove
-            }
-        }
-    }
-
-    //When we delete a black node with two black children (remember, null is black!). If the
-    // sibling is black with at least one red child we must rotate. If the sibling is red, both of
-    // it's childen must be black (per the properties of red black trees) and it must be rotated
-    // based on whether the sibling is the left or right child.
-    // When we remove a black node that has two black childen, whose sibling also has two black
-    // childern we must recolor the tree.
-    // There are two cases. The simple case is that the parent is red. In this case the parent
-    // becomes black and the sibling becomes red. The more complex case is when the parent is also
-    // black. In this case the parent remains black, the sibling becomes red, and the function is
-    // recalled on the parent. If we hit the root, the root remains black, the sibling becomes red,
-    // and then we are done.
-    // index is the index of the parent node, is_left lets us know if the sibling is the left node.
-    fn remove_helper(&mut self, index: Option<usize>) {
-        let sibling_is_left: bool;
-        let sibling_index: Option<usize>;
-        let parent_color: bool;
-        let p_idx: usize;
-        match index {
-            Some(idx) => {
-                match self.edge_list[idx][0] {
-                    Some(p) => {
-                        parent_color = self.color[p];
-                        p_idx = p;
-                        if self.edge_list[p][1] == index {
-                            if self.edge_list[p][2].is_none(){ // No sibling, move up the tree
-                                self.remove_helper(self.edge_list[idx][0]);
-                            }
-                            sibling_is_left = false;
-                            sibling_index = self.edge_list[p][2];
-                        } else {
-                            if self.edge_list[p][1].is_none(){ // No sibling, move up the tree
-                                self.remove_helper(self.edge_list[idx][0]);
-                            }
-                            sibling_is_left = true;
-                            sibling_index = self.edge_list[p][1];
-                        }
-                    }
-                    None => { // We are at the root
-                        return;
-                    }
-                }
+            parent = x;
+            x = self.edge_list[i][dir];
+        }
+        let (lc, rc) = if taller_is_left { (x, right) } else { (left, x) };
+        self.edge_list[idx].copy_from_slice(&[parent, lc, rc]);
+        for child in [lc, rc].into_iter().flatten() {
+            self.edge_list[child][0] = Some(idx);
+        }
+        match parent {
+            Some(p) => {
+                self.edge_list[p][dir] = Some(idx);
             }
             None => {
-                unreachable!();
+                self.root = Some(idx);
             }
         }
-        match sibling_index {
-            Some(s_idx) => {
-                let left_color: bool; // stores color for left child
-                let right_color: bool; // stores color for right child
-                match self.edge_list[s_idx][1] {
-                    Some(left_child) => {
-                        left_color = self.color[left_child];
-                    }
-                    None => { // None is black 
-                        left_color = false;
-                    }
-                }
-                match self.edge_list[s_idx][2] {
-                    Some(right_child) => {
-                        right_color = self.color[right_child];
-                    }
-                    None => { // None is black
-                        right_color = false;
-                    }
-                }
-                if !self.color[s_idx] { // If black
-                    if sibling_is_left{ // node is left sibling
-                        if left_color { // left child is red
-                            self.left_left_rotation(s_idx);
-                        } else if right_color {
-                            self.left_right_rotation(s_idx);
-                        } else { // both children are black
-                            if parent_color { // parent is red
-                                self.color[s_idx] = true; // sibling becomes red
-                                self.color[p_idx] = false; // parent becomes black
-                            } else {
-                                self.color[s_idx] = true; // sibling becomes red
-                                self.remove_helper(self.edge_list[s_idx][0]); // recall on parent
-                            }
-                        }
-                    } else { // must be right sibling
-                        if right_color { // left child is red
-                            self.right_right_rotation(s_idx);
-                        } else if left_color {
-                            self.right_left_rotation(s_idx);
-                        } else { // both children are black
-                            if parent_color { // parent is red
-                                self.color[s_idx] = true; // sibling becomes red
-                                self.color[p_idx] = false; // parent becomes black
-                            } else {
-                                self.color[s_idx] = true; // sibling becomes red
-                                self.remove_helper(self.edge_list[s_idx][0]); // recall on parent
-                            }
-                        }
-                    }
-                } else { // Is red
-                    if sibling_is_left {
-                        self.left_left_rotation(s_idx);
-                    } else {
-                        self.right_right_rotation(s_idx);
-                    }
-                }
-            }
-            None => { // should have already moved up the tree if sibling is None
-                unreachable!();
-            }
+        self.update_sizes(Some(idx));
+        self.insert_rebalance(idx);
+    }
+
+    /// Moves every element of other into this tree, leaving other empty. If both trees hold an
+    /// equal element, the one already in this tree is kept. Both trees are walked in sorted order
+    /// and merged, then this tree is rebuilt in its own vectors from the result, which is
+    /// O(n + m) rather than inserting each element of other
+    pub fn append(&mut self, other: &mut Tree<T>) {
+        if other.is_empty() {
+            return;
         }
+        let capacity = self.len + other.len;
+        let (merged, _) = merge_sorted(self.drain(), other.drain(), capacity); // both sides come out of trees in order
+        self.fill_sorted(merged);
     }
 
+    /// Splits the tree in two at `at`. Every element greater than or equal to at is moved into
+    /// the returned tree and everything smaller stays in this one. Both halves are rebuilt from
+    /// their sorted elements in O(n) and only allocate what they hold, so the vectors of the
+    /// original tree are released rather than carried along by either half
+    pub fn split_off(&mut self, at: &T) -> Tree<T> {
+        self.split_off_borrowed(at)
+    }
 
-    // Finds the in order successor.
-    fn get_in_order_successor(&self, index: usize) -> usize {
-        let ret;
-        match self.edge_list[index][1] {
-            Some(idx) => {
-                ret = self.get_in_order_successor(idx);
-            }
-            None => {
-                // Left child is none, we must be at the smallest element in the right branch
-                ret = index;
-            }
-        }
-        ret
+    // split_off for anything T borrows as, so the map can split its pairs at a key
+    pub(crate) fn split_off_borrowed<Q: std::cmp::PartialOrd + ?Sized>(&mut self, at: &Q) -> Tree<T>
+    where
+        T: Borrow<Q>,
+    {
+        let below = self.prefix_len(|d| d.borrow() < at);
+        let mut old = std::mem::take(self);
+        let mut drain = old.drain();
+        *self = Tree::from_sorted_iter(drain.by_ref().take(below));
+        Tree::from_sorted_iter(drain)
     }
 
-    ///Function to search the tree for a given value. Returns true if found, false otherwise.
-    pub fn contains(&self, input: T) -> bool{
-        self.contains_recursive(self.root, &input).is_some() // If the item is found, an index will be returned
+    /// Builds a tree out of strictly ascending values in O(n), without any rebalancing.
+    /// The values are stored in order and then linked into a balanced shape: every level but the
+    /// last is full, so making the nodes on the last level red and the rest black gives every
+    /// path the same black height. Input that isn't strictly ascending is caught by a debug
+    /// assertion, in release builds it produces a tree whose lookups give unspecified results
+    pub fn from_sorted_iter<I: IntoIterator<Item = T>>(iter: I) -> Tree<T> {
+        let sorted = iter.into_iter();
+        let mut tree: Tree<T> = Tree::with_capacity(sorted.size_hint().0);
+        tree.fill_sorted(sorted);
+        tree
     }
 
-    fn contains_recursive(&self, index: Option<usize>, input: &T) -> Option<usize> {
-        let mut ret: Option<usize> = None;
-        match index {
-            Some(idx) => {
-                let d = self.graph[idx].data.as_ref();
-                if d == input { // Item found, return index
-                    ret = index;
-                } else if d > input { // check left
-                    ret = self.contains_recursive(self.edge_list[idx][1], input);
-                } else { // check right
-                    ret = self.contains_recursive(self.edge_list[idx][2], input);
-                }
-            }
-            None => {
-                // Do nothing
-            }
+    // Builds the balanced tree of from_sorted_iter in this tree's vectors, which must be empty
+    // (as left by clear or drain) so the values land at indices 0..n in order
+    fn fill_sorted(&mut self, sorted: impl IntoIterator<Item = T>) {
+        debug_assert!(self.graph.is_empty(), "fill_sorted needs an empty tree");
+        for input in sorted {
+            debug_assert!(self.len == 0 || self.value(self.len - 1) < &input, "values must be strictly ascending");
+            self.alloc_node(input);
         }
-        ret
+        let height = (usize::BITS - self.len.leading_zeros()) as usize; // levels in the balanced tree
+        self.root = self.link_sorted(0, self.len, None, 0, height.saturating_sub(1));
     }
 
-    /// in_order traverses the tree and returns a list of the nodes in depth first order
-    pub fn in_order(&self) -> LinkedList<&T> {
-        let mut ll: LinkedList<&T> = LinkedList::new();
-        if self.root.is_none() { // Tree is empty
-           // Do nothing 
-        } else {
-            ll.append(& mut self.ino_recursive(self.root));
+    // Links the nodes stored at indices lo..hi into a balanced branch under parent and returns
+    // the index of its root. Nodes at red_depth are colored red, all others black
+    fn link_sorted(&mut self, lo: usize, hi: usize, parent: Option<usize>, depth: usize, red_depth: usize) -> Option<usize> {
+        if lo >= hi {
+            return None;
         }
-        ll
+        let mid = lo + (hi - lo) / 2;
+        self.edge_list[mid][0] = parent;
+        self.edge_list[mid][1] = self.link_sorted(lo, mid, Some(mid), depth + 1, red_depth);
+        self.edge_list[mid][2] = self.link_sorted(mid + 1, hi, Some(mid), depth + 1, red_depth);
+        self.color[mid] = depth > 0 && depth == red_depth; // the root is always black
+        self.subtree_size[mid] = hi - lo;
+        Some(mid)
     }
-    // Helper function to recursively build the linked list for in order traversal
-    fn ino_recursive(&self, index: Option<usize>) -> LinkedList<&T> {
-        let mut ll :LinkedList<&T> = LinkedList::new();
-        match index {
-            Some(i) => {
-                let left: Option<usize> = self.edge_list[i][1];
-                let right: Option<usize> = self.edge_list[i][2];
-                ll.append(&mut self.ino_recursive(left));
-                ll.push_back(self.graph[i].data.as_ref());
-                ll.append(&mut self.ino_recursive(right));
-            }
-            None => { // Nothing to recurse into
-                // Do nothing
+
+    // Moves every node of other into this tree's vectors, keeping other's shape and colors.
+    // The moved nodes are not linked to this tree's root, the index of their root is returned
+    fn adopt(&mut self, other: &mut Tree<T>) -> Option<usize> {
+        let mut new_index: Vec<Option<usize>> = vec![None; other.graph.len()];
+        let mut stack: Vec<usize> = other.root.into_iter().collect();
+        let mut moved: Vec<usize> = Vec::with_capacity(other.len);
+        while let Some(i) = stack.pop() {
+            let data = match other.graph[i].data.take() {
+                Some(d) => *d,
+                None => unreachable!(),
+            };
+            new_index[i] = Some(self.alloc_node(data));
+            moved.push(i);
+            stack.extend(other.edge_list[i][1]);
+            stack.extend(other.edge_list[i][2]);
+        }
+        for i in moved { // relink using the new indices
+            let idx = new_index[i].unwrap();
+            self.edge_list[idx] = other.edge_list[i].iter().map(|e| e.and_then(|j| new_index[j])).collect();
+            self.color[idx] = other.color[i];
+            self.subtree_size[idx] = other.subtree_size[i];
+        }
+        other.root.and_then(|r| new_index[r])
+    }
+
+    // Number of black nodes from index down to a leaf. Every path has the same count, so this
+    // just follows the left edge
+    fn spine_black_height(&self, index: Option<usize>) -> usize {
+        let mut height: usize = 0;
+        let mut index = index;
+        while let Some(i) = index {
+            if !self.color[i] {
+                height += 1;
             }
+            index = self.edge_list[i][1];
         }
-        ll
+        height
     }
 
-    /// Pre order traversal of the tree
-    pub fn pre_order(&self) -> LinkedList<&T> {
-        let mut ll: LinkedList<&T> = LinkedList::new();
-        if self.root.is_none() { // Tree is empty
-           // Do nothing 
+    /// Moves the elements to the front of the vectors so that the slots freed by removals end up
+    /// at the back, then drops those slots. The shape of the tree is unchanged, the nodes just
+    /// get new indices. Capacity is kept, follow up with `shrink_to_fit` to release it
+    pub fn compact(&mut self) {
+        let len = self.len;
+        let mut is_free = vec![false; self.graph.len()];
+        for &i in self.empty.iter() {
+            is_free[i] = true;
+        }
+        let holes: Vec<usize> = (0..len).filter(|&i| is_free[i]).collect(); // free slots that need filling
+        let movers: Vec<usize> = (len..self.graph.len()).filter(|&i| !is_free[i]).collect(); // nodes past the end
+        for (hole, node) in holes.into_iter().zip(movers) {
+            self.move_node(node, hole);
+        }
+        self.graph.truncate(len);
+        self.edge_list.truncate(len);
+        self.color.truncate(len);
+        self.subtree_size.truncate(len);
+        self.empty.clear();
+    }
+
+    // Makes room in the vectors for additional more nodes, counting the free slots as room
+    pub(crate) fn reserve(&mut self, additional: usize) {
+        let additional = additional.saturating_sub(self.empty.len());
+        self.graph.reserve(additional);
+        self.edge_list.reserve(additional);
+        self.color.reserve(additional);
+        self.subtree_size.reserve(additional);
+    }
+
+    /// Shrinks the capacity of the vectors as much as possible
+    pub fn shrink_to_fit(&mut self) {
+        self.graph.shrink_to_fit();
+        self.edge_list.shrink_to_fit();
+        self.color.shrink_to_fit();
+        self.subtree_size.shrink_to_fit();
+    }
+
+    /// Removes every element, dropping each of them. The vectors are truncated rather than
+    /// freed, so their capacity is kept and the tree can be refilled without reallocating
+    pub fn clear(&mut self) {
+        self.graph.clear();
+        self.edge_list.clear();
+        self.color.clear();
+        self.subtree_size.clear();
+        self.empty.clear();
+        self.root = None;
+        self.len = 0;
+    }
+
+    /// Compacts and shrinks the tree, but only if the fraction of slots holding an element has
+    /// dropped below load_factor, e.g. 0.5 reclaims memory once more than half of the slots were
+    /// freed by removals. Returns true if the compaction ran
+    pub fn maybe_compact(&mut self, load_factor: f64) -> bool {
+        if self.graph.is_empty() {
+            return false;
+        }
+        if (self.len as f64 / self.graph.len() as f64) < load_factor {
+            self.compact();
+            self.shrink_to_fit();
+            true
         } else {
-            ll.append(& mut self.pre_recursive(self.root));
+            false
         }
-        ll
     }
 
-    fn pre_recursive(&self, index: Option<usize>) -> LinkedList<&T> {
-        let mut ll :LinkedList<&T> = LinkedList::new();
-        match index {
-            Some(i) => {
-                let left: Option<usize> = self.edge_list[i][1];
-                let right: Option<usize> = self.edge_list[i][2];
-                ll.push_back(self.graph[i].data.as_ref());
-                ll.append(&mut self.pre_recursive(left));
-                ll.append(&mut self.pre_recursive(right));
+    // Moves the node at from into the free slot at to, updating every link that pointed to it
+    fn move_node(&mut self, from: usize, to: usize) {
+        self.graph.swap(from, to);
+        self.edge_list.swap(from, to);
+        self.color.swap(from, to);
+        self.subtree_size.swap(from, to);
+        match self.edge_list[to][0] {
+            Some(p) => {
+                if self.edge_list[p][1] == Some(from) {
+                    self.edge_list[p][1] = Some(to);
+                } else {
+                    self.edge_list[p][2] = Some(to);
+                }
             }
-            None => { // Nothing to recurse into
-                // Do nothing
+            None => {
+                self.root = Some(to);
             }
         }
-        ll
-    }
-    
-    /// Post order traversal of the tree
-    pub fn post_order(&self) -> LinkedList<&T> {
-        let mut ll: LinkedList<&T> = LinkedList::new();
-        if self.root.is_none() { // Tree is empty
-           // Do nothing 
-        } else {
-            ll.append(& mut self.post_recursive(self.root));
+        for child in [self.edge_list[to][1], self.edge_list[to][2]].into_iter().flatten() {
+            self.edge_list[child][0] = Some(to);
         }
-        ll
     }
 
-    fn post_recursive(&self, index: Option<usize>) -> LinkedList<&T> {
-        let mut ll :LinkedList<&T> = LinkedList::new();
-        match index {
-            Some(i) => {
-                let left: Option<usize> = self.edge_list[i][1];
-                let right: Option<usize> = self.edge_list[i][2];
-                ll.append(&mut self.post_recursive(left));
-                ll.append(&mut self.post_recursive(right));
-                ll.push_back(self.graph[i].data.as_ref());
+    /// Removes the smallest element. The node is found by walking down the left edge of the
+    /// tree, so no value comparisons are made. Returns false if the tree was empty
+    pub fn remove_min(&mut self) -> bool {
+        match self.root {
+            Some(r) => {
+                let idx = self.get_in_order_successor(r);
+                self.remove_index(idx);
+                true
             }
-            None => { // Nothing to recurse into
-                // Do nothing
+            None => false,
+        }
+    }
+
+    /// Removes the largest element. The node is found by walking down the right edge of the
+    /// tree, so no value comparisons are made. Returns false if the tree was empty
+    pub fn remove_max(&mut self) -> bool {
+        match self.root {
+            Some(r) => {
+                let idx = self.get_in_order_predecessor(r);
+                self.remove_index(idx);
+                true
             }
+            None => false,
+        }
+    }
+
+    /// Removes the smallest element and returns it, or None if the tree is empty.
+    /// Repeatedly calling this drains the tree in ascending order, like a priority queue
+    pub fn pop_min(&mut self) -> Option<T> {
+        self.root.map(|r| {
+            let idx = self.get_in_order_successor(r);
+            self.remove_index(idx)
+        })
+    }
+
+    /// Removes the largest element and returns it, or None if the tree is empty
+    pub fn pop_max(&mut self) -> Option<T> {
+        self.root.map(|r| {
+            let idx = self.get_in_order_predecessor(r);
+            self.remove_index(idx)
+        })
+    }
+
+    // Unlinks the node stored at index from the tree, rebalances and hands back its value. Nodes are
+    // relinked rather than having their data moved around, so the index of every other element stays the same
+    pub(crate) fn remove_index(&mut self, index: usize) -> T {
+        let removed_black: bool; // color of the node that is unlinked from its position
+        let child: Option<usize>; // node that moves up into the unlinked position (may be None)
+        let child_parent: Option<usize>; // parent of the unlinked position
+        match (self.edge_list[index][1], self.edge_list[index][2]) {
+            (Some(lc), Some(rc)) => { // Both children exist
+                // The in order successor must be in the right branch and has no left child
+                let ios = self.get_in_order_successor(rc);
+                removed_black = !self.color[ios];
+                child = self.edge_list[ios][2];
+                if self.edge_list[ios][0] == Some(index) { // successor is the right child of index
+                    child_parent = Some(ios);
+                } else {
+                    child_parent = self.edge_list[ios][0];
+                    self.replace_child(ios, child); // detach the successor from its current position
+                    self.edge_list[ios][2] = Some(rc);
+                    self.edge_list[rc][0] = Some(ios);
+                }
+                // The successor takes the place of index along with its left branch and color
+                self.replace_child(index, Some(ios));
+                self.edge_list[ios][1] = Some(lc);
+                self.edge_list[lc][0] = Some(ios);
+                self.color[ios] = self.color[index];
+            }
+            (lc, rc) => { // At most one child, splice the node out
+                child = lc.or(rc);
+                child_parent = self.edge_list[index][0];
+                removed_black = !self.color[index];
+                self.replace_child(index, child);
+            }
+        }
+        self.update_sizes(child_parent); // every ancestor of the unlinked position lost a node
+        if removed_black {
+            self.remove_rebalance(child, child_parent);
+        }
+        self.edge_list[index] = vec![None, None, None];
+        self.len -= 1;
+        self.empty.push_back(index); // Mark index as free in the stack
+        match self.graph[index].data.take() { // the slot must not keep the value alive
+            Some(data) => *data,
+            None => unreachable!(), // index was linked into the tree, so it holds data
+        }
+    }
+
+    // Puts new in the position of old, under old's parent or as the root
+    fn replace_child(&mut self, old: usize, new: Option<usize>) {
+        let parent = self.edge_list[old][0];
+        match parent {
+            Some(p) => {
+                if self.edge_list[p][1] == Some(old) {
+                    self.edge_list[p][1] = new;
+                } else {
+                    self.edge_list[p][2] = new;
+                }
+            }
+            None => {
+                self.root = new;
+            }
+        }
+        if let Some(n) = new {
+            self.edge_list[n][0] = parent;
+        }
+    }
+
+    // When a black node is unlinked every path through its position is one black node short.
+    // index is the node that moved into that position (None is black) and parent its parent.
+    // If index is red it is simply painted black. Otherwise we look at the sibling:
+    //      A red sibling is rotated up so that index gets a black sibling instead.
+    //      A black sibling with two black children becomes red, which moves the shortage up to
+    //      the parent, and we continue from there.
+    //      A black sibling with a red child is rotated (twice if the red child is on the inside)
+    //      so that the red child can be painted black to restore the missing black node.
+    fn remove_rebalance(&mut self, index: Option<usize>, parent: Option<usize>) {
+        let mut index = index;
+        let mut parent = parent;
+        while index != self.root && !self.is_red(index) {
+            let p = match parent {
+                Some(p) => p,
+                None => unreachable!(), // only the root has no parent
+            };
+            // The sibling must exist, its side of the tree has more black nodes than index's side
+            if self.edge_list[p][1] == index { // sibling is the right child
+                let mut s_idx = self.edge_list[p][2].unwrap();
+                if self.color[s_idx] { // sibling is red
+                    self.color[s_idx] = false;
+                    self.color[p] = true;
+                    self.right_right_rotation(s_idx);
+                    s_idx = self.edge_list[p][2].unwrap();
+                }
+                if !self.is_red(self.edge_list[s_idx][1]) && !self.is_red(self.edge_list[s_idx][2]) { // both children are black
+                    self.color[s_idx] = true; // sibling becomes red
+                    index = Some(p); // recall on parent
+                    parent = self.edge_list[p][0];
+                } else {
+                    if !self.is_red(self.edge_list[s_idx][2]) { // only the inner child is red
+                        let inner = self.edge_list[s_idx][1].unwrap();
+                        self.color[inner] = false;
+                        self.color[s_idx] = true;
+                        self.left_left_rotation(inner);
+                        s_idx = inner;
+                    }
+                    self.color[s_idx] = self.color[p];
+                    self.color[p] = false;
+                    if let Some(outer) = self.edge_list[s_idx][2] {
+                        self.color[outer] = false;
+                    }
+                    self.right_right_rotation(s_idx);
+                    index = self.root; // We are done
+                }
+            } else { // sibling is the left child
+                let mut s_idx = self.edge_list[p][1].unwrap();
+                if self.color[s_idx] { // sibling is red
+                    self.color[s_idx] = false;
+                    self.color[p] = true;
+                    self.left_left_rotation(s_idx);
+                    s_idx = self.edge_list[p][1].unwrap();
+                }
+                if !self.is_red(self.edge_list[s_idx][1]) && !self.is_red(self.edge_list[s_idx][2]) { // both children are black
+                    self.color[s_idx] = true; // sibling becomes red
+                    index = Some(p); // recall on parent
+                    parent = self.edge_list[p][0];
+                } else {
+                    if !self.is_red(self.edge_list[s_idx][1]) { // only the inner child is red
+                        let inner = self.edge_list[s_idx][2].unwrap();
+                        self.color[inner] = false;
+                        self.color[s_idx] = true;
+                        self.right_right_rotation(inner);
+                        s_idx = inner;
+                    }
+                    self.color[s_idx] = self.color[p];
+                    self.color[p] = false;
+                    if let Some(outer) = self.edge_list[s_idx][1] {
+                        self.color[outer] = false;
+                    }
+                    self.left_left_rotation(s_idx);
+                    index = self.root; // We are done
+                }
+            }
+        }
+        if let Some(idx) = index {
+            self.color[idx] = false;
+        }
+    }
+
+    // The element stored at idx, which must not be a free slot
+    pub(crate) fn value(&self, idx: usize) -> &T {
+        match self.graph[idx].data.as_deref() {
+            Some(d) => d,
+            None => unreachable!(), // only indices on the empty stack hold no data
+        }
+    }
+
+    // Mutable access to the element at idx. Only for the map, which never changes the part of an
+    // element that decides its order
+    pub(crate) fn value_mut(&mut self, idx: usize) -> &mut T {
+        match self.graph[idx].data.as_deref_mut() {
+            Some(d) => d,
+            None => unreachable!(), // only indices on the empty stack hold no data
+        }
+    }
+
+    // None branches are black
+    fn is_red(&self, index: Option<usize>) -> bool {
+        match index {
+            Some(idx) => self.color[idx],
+            None => false,
+        }
+    }
+
+    // Number of nodes in the subtree rooted at index, a None branch is empty
+    fn size(&self, index: Option<usize>) -> usize {
+        match index {
+            Some(idx) => self.subtree_size[idx],
+            None => 0,
+        }
+    }
+
+    // Recomputes the size of the subtree rooted at idx from its children
+    fn update_size(&mut self, idx: usize) {
+        self.subtree_size[idx] = 1 + self.size(self.edge_list[idx][1]) + self.size(self.edge_list[idx][2]);
+    }
+
+    // Recomputes the subtree sizes from index up to the root, after a node was attached or unlinked
+    fn update_sizes(&mut self, index: Option<usize>) {
+        let mut index = index;
+        while let Some(idx) = index {
+            self.update_size(idx);
+            index = self.edge_list[idx][0];
+        }
+    }
+
+    // Finds the in order successor.
+    fn get_in_order_successor(&self, index: usize) -> usize {
+        match self.edge_list[index][1] {
+            Some(idx) => {
+                self.get_in_order_successor(idx)
+            }
+            None => {
+                // Left child is none, we must be at the smallest element in the right branch
+                index
+            }
+        }
+    }
+
+    // Finds the in order predecessor, i.e. the largest element in the branch starting at index.
+    fn get_in_order_predecessor(&self, index: usize) -> usize {
+        match self.edge_list[index][2] {
+            Some(idx) => {
+                self.get_in_order_predecessor(idx)
+            }
+            None => {
+                // Right child is none, we must be at the largest element in the branch
+                index
+            }
+        }
+    }
+
+    // Index of the next element in sorted order, found through the edges so no values are compared
+    fn next_index(&self, index: usize) -> Option<usize> {
+        step_in_order(&self.edge_list, index, true)
+    }
+
+    // Index of the previous element in sorted order, the mirror of next_index
+    fn prev_index(&self, index: usize) -> Option<usize> {
+        step_in_order(&self.edge_list, index, false)
+    }
+
+    // Walks every element in order handing out mutable references. Only for the map, whose
+    // values can change in place because the keys that order them are never handed out mutably
+    pub(crate) fn iter_mut(&mut self) -> IterMut<'_, T> {
+        let front = self.root.map(|r| self.get_in_order_successor(r));
+        let back = self.root.map(|r| self.get_in_order_predecessor(r));
+        IterMut { edges: &self.edge_list, nodes: self.graph.as_mut_ptr(), front, back, remaining: self.len, marker: PhantomData }
+    }
+
+    // Like retain but f gets each element mutably, again only for the map. f must leave the
+    // order of the element alone, every element is seen before any of them are removed
+    pub(crate) fn retain_mut<F: FnMut(&mut T) -> bool>(&mut self, f: F) {
+        let mut f = f;
+        let mut doomed: Vec<usize> = Vec::new();
+        let mut next = self.root.map(|r| self.get_in_order_successor(r));
+        while let Some(idx) = next {
+            if !f(self.value_mut(idx)) {
+                doomed.push(idx);
+            }
+            next = self.next_index(idx);
+        }
+        for idx in doomed {
+            self.remove_index(idx);
+        }
+    }
+
+    // Index of the next element in pre order. Children come first, otherwise climb until we come
+    // up out of a left branch whose parent has a right branch, that right child is next
+    fn pre_order_next(&self, index: usize) -> Option<usize> {
+        if let Some(child) = self.edge_list[index][1].or(self.edge_list[index][2]) {
+            return Some(child);
+        }
+        let mut child = index;
+        let mut parent = self.edge_list[index][0];
+        while let Some(p) = parent {
+            if self.edge_list[p][1] == Some(child) && self.edge_list[p][2].is_some() {
+                return self.edge_list[p][2];
+            }
+            child = p;
+            parent = self.edge_list[p][0];
+        }
+        None
+    }
+
+    // Index of the first element in post order of the branch at index, found by going down
+    // preferring left children until a leaf is reached
+    fn post_order_first(&self, index: usize) -> usize {
+        match self.edge_list[index][1].or(self.edge_list[index][2]) {
+            Some(child) => self.post_order_first(child),
+            None => index,
+        }
+    }
+
+    // Index of the next element in post order. A left child is followed by its parent's right
+    // branch if there is one, otherwise the parent comes next
+    fn post_order_next(&self, index: usize) -> Option<usize> {
+        let parent = self.edge_list[index][0]?;
+        match self.edge_list[parent][2] {
+            Some(rc) if self.edge_list[parent][1] == Some(index) => Some(self.post_order_first(rc)),
+            _ => Some(parent),
+        }
+    }
+
+    // Index of the element before the gap in front of next, where None means the end of the tree
+    fn index_before(&self, next: Option<usize>) -> Option<usize> {
+        match next {
+            Some(idx) => self.prev_index(idx),
+            None => self.root.map(|r| self.get_in_order_predecessor(r)), // at the end, the largest element
+        }
+    }
+
+    /// Returns the first (smallest) element in sorted order, or None if the tree is empty.
+    /// Walks down the left edge of the tree, so this is O(height) and doesn't allocate
+    pub fn first(&self) -> Option<&T> {
+        self.root.map(|r| self.value(self.get_in_order_successor(r)))
+    }
+
+    /// Returns the last (largest) element in sorted order, or None if the tree is empty.
+    /// Walks down the right edge of the tree, so this is O(height) and doesn't allocate
+    pub fn last(&self) -> Option<&T> {
+        self.root.map(|r| self.value(self.get_in_order_predecessor(r)))
+    }
+
+    /// Returns the smallest element, or None if the tree is empty. Same as `first`.
+    /// When T is Ord, `tree.min()` on an owned tree picks `Ord::min` instead, so write
+    /// `Tree::min(&tree)` or call it through a reference
+    pub fn min(&self) -> Option<&T> {
+        self.first()
+    }
+
+    /// Returns the largest element, or None if the tree is empty. Same as `last`, and shadowed
+    /// by `Ord::max` the same way `min` is
+    pub fn max(&self) -> Option<&T> {
+        self.last()
+    }
+
+    /// Returns the element stored at the root of the tree, or None if the tree is empty
+    pub fn peek_root(&self) -> Option<&T> {
+        self.root.map(|i| self.value(i))
+    }
+
+    /// Returns the color of the root, or None if the tree is empty. The root of a valid tree is
+    /// always black
+    pub fn root_color(&self) -> Option<Color> {
+        self.root.map(|i| Color::from(self.color[i]))
+    }
+
+    ///Function to search the tree for a given value. Returns true if found, false otherwise.
+    /// Like the std collections, value can be any borrowed form of T, e.g. a `Tree<String>`
+    /// can be searched with a `&str` without allocating
+    pub fn contains<Q: std::cmp::PartialOrd + ?Sized>(&self, value: &Q) -> bool
+    where
+        T: Borrow<Q>,
+    {
+        self.contains_recursive(self.root, value).is_some() // If the item is found, an index will be returned
+    }
+
+    /// Looks up value and returns a reference to the stored element, or None if it is not in
+    /// the tree. Useful when elements that compare equal still differ, e.g. a struct ordered
+    /// by an id field. value can be any borrowed form of T, like for `contains`
+    pub fn get<Q: std::cmp::PartialOrd + ?Sized>(&self, value: &Q) -> Option<&T>
+    where
+        T: Borrow<Q>,
+    {
+        self.contains_recursive(self.root, value).map(|idx| self.value(idx))
+    }
+
+    /// Looks up every value in sorted_queries and returns the stored elements in the same order,
+    /// None for the ones that aren't in the tree. Because the queries are ascending, one walk
+    /// through the tree answers all of them in O(n + m) instead of descending from the root m
+    /// times. Few queries against a large tree are still looked up one at a time, and so are
+    /// queries that turn out not to be sorted (which also trips a debug assertion)
+    pub fn get_many<'a>(&'a self, sorted_queries: &[T]) -> Vec<Option<&'a T>> {
+        let sorted = sorted_queries.windows(2).all(|w| w[0] <= w[1]);
+        debug_assert!(sorted, "get_many queries must be in ascending order");
+        if !sorted || sorted_queries.len() * LOPSIDED_RATIO < self.len {
+            return sorted_queries.iter().map(|q| self.get(q)).collect();
+        }
+        let mut iter = self.iter().peekable();
+        sorted_queries
+            .iter()
+            .map(|q| {
+                while iter.next_if(|&x| x < q).is_some() {} // skip everything below the query
+                iter.peek().copied().filter(|&x| x == q)
+            })
+            .collect()
+    }
+
+    /// Like `get_many`, but only says whether each query is in the tree
+    pub fn contains_many(&self, sorted_queries: &[T]) -> Vec<bool> {
+        self.get_many(sorted_queries).into_iter().map(|found| found.is_some()).collect()
+    }
+
+    // Index of the element equal to value, if there is one
+    pub(crate) fn find_index<Q: std::cmp::PartialOrd + ?Sized>(&self, value: &Q) -> Option<usize>
+    where
+        T: Borrow<Q>,
+    {
+        self.contains_recursive(self.root, value)
+    }
+
+    fn contains_recursive<Q: std::cmp::PartialOrd + ?Sized>(&self, index: Option<usize>, input: &Q) -> Option<usize>
+    where
+        T: Borrow<Q>,
+    {
+        let mut ret: Option<usize> = None;
+        match index {
+            Some(idx) => {
+                let d: &Q = self.value(idx).borrow();
+                if d == input { // Item found, return index
+                    ret = index;
+                } else if d > input { // check left
+                    ret = self.contains_recursive(self.edge_list[idx][1], input);
+                } else { // check right
+                    ret = self.contains_recursive(self.edge_list[idx][2], input);
+                }
+            }
+            None => {
+                // Do nothing
+            }
+        }
+        ret
+    }
+
+    /// Searches the tree with a comparator instead of a value, like `slice::binary_search_by`.
+    /// f returns how an element compares to the target: `Less` if the element is smaller,
+    /// `Greater` if it is larger and `Equal` once it is found. f must agree with the order of
+    /// the tree, which lets values be looked up by a key without building a whole `T`
+    pub fn find_by<F: Fn(&T) -> Ordering>(&self, f: F) -> Option<&T> {
+        self.find_slot_by(self.root, f).ok().map(|idx| self.value(idx))
+    }
+
+    /// Returns the first element in sorted order for which pred is true, in a single descent
+    /// like `slice::partition_point`. pred must be monotone over the sorted order: false for
+    /// some prefix of the elements and true for all of the rest, otherwise the result is
+    /// meaningless. Returns None if pred is false for every element
+    pub fn partition_point<F: Fn(&T) -> bool>(&self, pred: F) -> Option<&T> {
+        let mut best: Option<&T> = None;
+        let mut index = self.root;
+        while let Some(idx) = index {
+            let d = self.value(idx);
+            if pred(d) { // candidate, check left for an earlier one
+                best = Some(d);
+                index = self.edge_list[idx][1];
+            } else { // the boundary is further right
+                index = self.edge_list[idx][2];
+            }
+        }
+        best
+    }
+
+    /// Returns the smallest element greater than value, or None if there is none.
+    /// value doesn't have to be in the tree
+    pub fn successor(&self, value: &T) -> Option<&T> {
+        self.partition_point(|d| d > value)
+    }
+
+    /// Returns the largest element less than value, or None if there is none.
+    /// value doesn't have to be in the tree
+    pub fn predecessor(&self, value: &T) -> Option<&T> {
+        self.last_in_prefix(|d| d < value)
+    }
+
+    /// Returns the largest element less than or equal to x, or None if there is none
+    pub fn floor(&self, x: &T) -> Option<&T> {
+        self.last_in_prefix(|d| d <= x)
+    }
+
+    /// Returns the smallest element greater than or equal to x, or None if there is none
+    pub fn ceiling(&self, x: &T) -> Option<&T> {
+        self.partition_point(|d| d >= x)
+    }
+
+    /// Returns the largest element strictly less than x. Same as `predecessor`
+    pub fn lower(&self, x: &T) -> Option<&T> {
+        self.predecessor(x)
+    }
+
+    /// Returns the smallest element strictly greater than x. Same as `successor`
+    pub fn higher(&self, x: &T) -> Option<&T> {
+        self.successor(x)
+    }
+
+    // Mirror of partition_point, returns the last element for which in_prefix holds. in_prefix
+    // must hold for every element before the first one it rejects
+    fn last_in_prefix<F: Fn(&T) -> bool>(&self, in_prefix: F) -> Option<&T> {
+        let mut best: Option<&T> = None;
+        let mut index = self.root;
+        while let Some(idx) = index {
+            let d = self.value(idx);
+            if in_prefix(d) { // candidate, check right for a later one
+                best = Some(d);
+                index = self.edge_list[idx][2];
+            } else { // the boundary is further left
+                index = self.edge_list[idx][1];
+            }
+        }
+        best
+    }
+
+    /// Returns true if any element of the tree falls within range. This only descends to the
+    /// smallest element satisfying the lower bound and checks it against the upper bound,
+    /// so it is O(height) and doesn't walk the range.
+    pub fn contains_range<R: RangeBounds<T>>(&self, range: R) -> bool {
+        match self.lower_bound_index(range.start_bound()) {
+            Some(idx) => {
+                within_upper(self.value(idx), range.end_bound())
+            }
+            None => { // Every element is below the range
+                false
+            }
+        }
+    }
+
+    /// Counts the elements of the tree that fall within range. This uses the subtree sizes to
+    /// count the elements below each end of the range, so it is O(log n) no matter how many
+    /// elements are in the range.
+    pub fn count_range<R: RangeBounds<T>>(&self, range: R) -> usize {
+        let up_to_end = self.prefix_len(|d| within_upper(d, range.end_bound()));
+        let before_start = self.prefix_len(|d| !within_lower(d, range.start_bound()));
+        up_to_end.saturating_sub(before_start) // an inverted range is empty
+    }
+
+    /// Removes every element that falls within range, rebalancing after each removal.
+    /// The in range nodes are collected first and then unlinked one at a time
+    pub fn remove_range<R: RangeBounds<T>>(&mut self, range: R) {
+        let mut doomed: Vec<usize> = Vec::with_capacity(self.count_range((range.start_bound(), range.end_bound())));
+        self.visit_range(self.root, range.start_bound(), range.end_bound(), &mut |idx| doomed.push(idx));
+        for idx in doomed { // removals only relink nodes, so the collected indices stay valid
+            self.remove_index(idx);
+        }
+    }
+
+    /// Returns references to the elements within range in sorted order. The vector is sized
+    /// exactly using `count_range` before it is filled, so it is allocated once
+    pub fn get_range<R: RangeBounds<T>>(&self, range: R) -> Vec<&T> {
+        let mut v: Vec<&T> = Vec::with_capacity(self.count_range((range.start_bound(), range.end_bound())));
+        self.visit_range(self.root, range.start_bound(), range.end_bound(), &mut |idx| v.push(self.value(idx)));
+        v
+    }
+
+    /// Folds the elements within range in sorted order, e.g. summing them with
+    /// `tree.fold_range(10..20, 0, |sum, x| sum + x)`. Only the branches that overlap the range
+    /// are visited and nothing is collected along the way
+    pub fn fold_range<R: RangeBounds<T>, B, F: FnMut(B, &T) -> B>(&self, range: R, init: B, f: F) -> B {
+        let mut f = f;
+        let mut acc: Option<B> = Some(init); // only empty while f is running
+        self.visit_range(self.root, range.start_bound(), range.end_bound(), &mut |idx| {
+            acc = acc.take().map(|a| f(a, self.value(idx)));
+        });
+        acc.unwrap()
+    }
+
+    /// Removes every element for which f returns false. f sees each element once, in sorted
+    /// order, before anything is removed
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, f: F) {
+        self.retain_range(.., f);
+    }
+
+    /// Inserts a clone of every element of other that isn't already in the tree. A few elements
+    /// going into a much larger tree are inserted one at a time after reserving room for them,
+    /// otherwise both trees are merged and the result rebuilt at its full size in O(n + m)
+    pub fn union_with(&mut self, other: &Tree<T>)
+    where
+        T: Clone,
+    {
+        if other.len * LOPSIDED_RATIO < self.len {
+            self.reserve(other.len);
+            for value in other.iter() {
+                self.insert(value.clone());
+            }
+        } else {
+            self.extend_from_sorted(other.iter().cloned());
+        }
+    }
+
+    /// Removes every element that is not also in other
+    pub fn intersect_with(&mut self, other: &Tree<T>) {
+        self.retain(|value| other.contains(value));
+    }
+
+    /// Removes every element that is also in other. When other is much smaller its elements are
+    /// removed one by one, otherwise every element of the tree is looked up in other
+    pub fn difference_with(&mut self, other: &Tree<T>) {
+        if other.len * LOPSIDED_RATIO < self.len {
+            self.remove_all(other.iter());
+        } else {
+            self.retain(|value| !other.contains(value));
+        }
+    }
+
+    /// Returns an iterator that removes the elements for which f returns true and yields them in
+    /// ascending order. Elements are only removed as the iterator reaches them, so dropping it
+    /// early leaves every element it hasn't visited yet in the tree
+    pub fn extract_if<F: FnMut(&T) -> bool>(&mut self, f: F) -> ExtractIf<'_, T, F> {
+        let next = self.root.map(|r| self.get_in_order_successor(r));
+        ExtractIf { tree: self, next, pred: f }
+    }
+
+    /// Removes every element and returns them as an iterator in ascending order. Values are
+    /// moved out of their slots as the walk goes without any rebalancing. The tree is empty once
+    /// the iterator is dropped, whether or not it was run to the end, and keeps its capacity
+    pub fn drain(&mut self) -> Drain<'_, T> {
+        let next = self.root.map(|r| self.get_in_order_successor(r));
+        let remaining = self.len;
+        // Detach the nodes up front, if the iterator is leaked the tree is left empty
+        // (with its slots lost until the next clear) rather than holding half moved out nodes
+        self.root = None;
+        self.len = 0;
+        Drain { tree: self, next, remaining }
+    }
+
+    /// Removes the elements within range for which f returns false. Elements outside the range
+    /// are left alone and never passed to f, so only the overlapping branches are visited
+    pub fn retain_range<R: RangeBounds<T>, F: FnMut(&T) -> bool>(&mut self, range: R, f: F) {
+        let mut f = f;
+        let mut doomed: Vec<usize> = Vec::new();
+        self.visit_range(self.root, range.start_bound(), range.end_bound(), &mut |idx| {
+            if !f(self.value(idx)) {
+                doomed.push(idx);
+            }
+        });
+        for idx in doomed { // removals only relink nodes, so the collected indices stay valid
+            self.remove_index(idx);
+        }
+    }
+
+    // Walks the branch at index in order, calling f with the index of every element within the
+    // bounds. Branches that lie entirely outside the bounds are never entered
+    fn visit_range<F: FnMut(usize)>(&self, index: Option<usize>, start: Bound<&T>, end: Bound<&T>, f: &mut F) {
+        if let Some(idx) = index {
+            let d = self.value(idx);
+            let after_start = within_lower(d, start);
+            let before_end = within_upper(d, end);
+            if after_start { // smaller elements may still be in range
+                self.visit_range(self.edge_list[idx][1], start, end, f);
+            }
+            if after_start && before_end {
+                f(idx);
+            }
+            if before_end { // larger elements may still be in range
+                self.visit_range(self.edge_list[idx][2], start, end, f);
+            }
+        }
+    }
+
+    /// Iterates over the elements within range in ascending order. The iterator starts at the
+    /// first element in range, found with a single descent, and follows the edges from there,
+    /// so nothing outside the range is visited. An empty or inverted range yields nothing
+    pub fn range<R: RangeBounds<T>>(&self, range: R) -> Range<'_, T> {
+        self.range_borrowed(range)
+    }
+
+    /// Returns a cursor at the gap just before the first element satisfying bound, e.g.
+    /// `Bound::Included(&x)` puts the first element greater than or equal to x after the cursor.
+    /// If every element is below the bound the cursor is at the end
+    pub fn lower_bound(&self, bound: Bound<&T>) -> Cursor<'_, T> {
+        Cursor { tree: self, next: self.lower_bound_index(bound) }
+    }
+
+    /// Returns a cursor at the gap just after the last element satisfying bound, e.g.
+    /// `Bound::Included(&x)` puts the last element less than or equal to x before the cursor.
+    /// If every element is above the bound the cursor is at the start
+    pub fn upper_bound(&self, bound: Bound<&T>) -> Cursor<'_, T> {
+        let next = match self.upper_bound_index(bound) {
+            Some(idx) => self.next_index(idx),
+            None => self.root.map(|r| self.get_in_order_successor(r)),
+        };
+        Cursor { tree: self, next }
+    }
+
+    /// Same as `lower_bound`, but the returned cursor can remove elements and insert new ones
+    /// at its position without searching the tree again
+    pub fn lower_bound_mut(&mut self, bound: Bound<&T>) -> CursorMut<'_, T> {
+        let next = self.lower_bound_index(bound);
+        CursorMut { tree: self, next }
+    }
+
+    /// Number of elements strictly less than value, i.e. the sorted position value would be
+    /// inserted at. Works whether or not value is in the tree, in O(log n)
+    pub fn lower_bound_position(&self, value: &T) -> usize {
+        self.prefix_len(|d| d < value)
+    }
+
+    /// Number of elements less than or equal to value, i.e. the sorted position just past value.
+    /// Works whether or not value is in the tree, in O(log n)
+    pub fn upper_bound_position(&self, value: &T) -> usize {
+        self.prefix_len(|d| d <= value)
+    }
+
+    /// Number of elements strictly less than value, read off the subtree sizes in O(log n).
+    /// Same as `lower_bound_position`
+    pub fn rank(&self, value: &T) -> usize {
+        self.prefix_len(|d| d < value)
+    }
+
+    // Counts the elements at the start of the sorted order for which in_prefix holds. in_prefix
+    // must hold for every element before the first one it rejects
+    fn prefix_len<F: Fn(&T) -> bool>(&self, in_prefix: F) -> usize {
+        let mut count: usize = 0;
+        let mut index = self.root;
+        while let Some(idx) = index {
+            if in_prefix(self.value(idx)) { // node and its left branch are in the prefix
+                count += 1 + self.size(self.edge_list[idx][1]);
+                index = self.edge_list[idx][2];
+            } else {
+                index = self.edge_list[idx][1];
+            }
+        }
+        count
+    }
+
+    /// Returns the k-th smallest element (starting from 0) in O(log n), or None if k is out of
+    /// range. `select(0)` is the minimum and `select(len() - 1)` the maximum
+    pub fn select(&self, k: usize) -> Option<&T> {
+        self.select_index(k).map(|idx| self.value(idx))
+    }
+
+    // Finds the index of the k-th smallest element (starting from 0) using the subtree sizes
+    fn select_index(&self, k: usize) -> Option<usize> {
+        let mut k = k;
+        let mut index = self.root;
+        while let Some(idx) = index {
+            let left_size = self.size(self.edge_list[idx][1]);
+            if k < left_size { // it's in the left branch
+                index = self.edge_list[idx][1];
+            } else if k == left_size { // exactly left_size elements are smaller
+                return index;
+            } else { // skip the left branch and this node
+                k -= left_size + 1;
+                index = self.edge_list[idx][2];
+            }
+        }
+        None
+    }
+
+    // Finds the index of the smallest element satisfying the lower bound of a range. The bound
+    // can be on any borrowed form of T, so the map can pass bounds on its keys
+    fn lower_bound_index<Q: std::cmp::PartialOrd + ?Sized>(&self, bound: Bound<&Q>) -> Option<usize>
+    where
+        T: Borrow<Q>,
+    {
+        let mut best: Option<usize> = None;
+        let mut index = self.root;
+        while let Some(idx) = index {
+            if within_lower(self.value(idx).borrow(), bound) { // candidate, check left for a smaller one
+                best = index;
+                index = self.edge_list[idx][1];
+            } else { // too small, check right
+                index = self.edge_list[idx][2];
+            }
+        }
+        best
+    }
+
+    // Finds the index of the largest element satisfying the upper bound of a range
+    fn upper_bound_index<Q: std::cmp::PartialOrd + ?Sized>(&self, bound: Bound<&Q>) -> Option<usize>
+    where
+        T: Borrow<Q>,
+    {
+        let mut best: Option<usize> = None;
+        let mut index = self.root;
+        while let Some(idx) = index {
+            if within_upper(self.value(idx).borrow(), bound) { // candidate, check right for a larger one
+                best = index;
+                index = self.edge_list[idx][2];
+            } else { // too large, check left
+                index = self.edge_list[idx][1];
+            }
+        }
+        best
+    }
+
+    // Where a range over a borrowed form of T starts and ends, and how many elements it holds
+    fn range_ends<Q: std::cmp::PartialOrd + ?Sized>(&self, start: Bound<&Q>, end: Bound<&Q>) -> (Option<usize>, Option<usize>, usize)
+    where
+        T: Borrow<Q>,
+    {
+        let up_to_end = self.prefix_len(|d| within_upper(d.borrow(), end));
+        let before_start = self.prefix_len(|d| !within_lower(d.borrow(), start));
+        // An inverted range is empty
+        (self.lower_bound_index(start), self.upper_bound_index(end), up_to_end.saturating_sub(before_start))
+    }
+
+    // Same as range, but the bounds are on a borrowed form of T, which is how the map ranges
+    // over its keys
+    pub(crate) fn range_borrowed<Q: std::cmp::PartialOrd + ?Sized, R: RangeBounds<Q>>(&self, range: R) -> Range<'_, T>
+    where
+        T: Borrow<Q>,
+    {
+        let (front, back, remaining) = self.range_ends(range.start_bound(), range.end_bound());
+        Range { tree: self, front, back, remaining }
+    }
+
+    // Mutable counterpart of range_borrowed, for the map's range_mut
+    pub(crate) fn range_mut_borrowed<Q: std::cmp::PartialOrd + ?Sized, R: RangeBounds<Q>>(&mut self, range: R) -> IterMut<'_, T>
+    where
+        T: Borrow<Q>,
+    {
+        let (front, back, remaining) = self.range_ends(range.start_bound(), range.end_bound());
+        IterMut { edges: &self.edge_list, nodes: self.graph.as_mut_ptr(), front, back, remaining, marker: PhantomData }
+    }
+
+    /// Applies a single recorded operation to the tree
+    pub fn apply(&mut self, op: Op<T>) {
+        match op {
+            Op::Insert(input) => {
+                self.insert(input);
+            }
+            Op::Remove(elem) => {
+                self.remove(&elem);
+            }
+        }
+    }
+
+    /// Builds a tree by replaying a sequence of operations in order, starting from an empty tree
+    pub fn from_operations(ops: impl IntoIterator<Item = Op<T>>) -> Tree<T> {
+        let mut tree = Tree::new();
+        for op in ops {
+            tree.apply(op);
+        }
+        tree
+    }
+
+    /// Checks the red-black properties: the root is black, no red node has a red child, and every
+    /// path from a node down to the leaves passes through the same number of black nodes.
+    /// Meant to be asserted after every operation while fuzzing
+    pub fn is_valid_red_black(&self) -> bool {
+        !self.is_red(self.root) && self.black_height(self.root).is_some()
+    }
+
+    /// Checks that two trees have exactly the same shape: starting from the roots, every pair of
+    /// nodes holds equal values, has the same color and the same children present. Trees with
+    /// the same contents but different shapes are not structurally equal, which makes this
+    /// useful for pinning the exact result of a rotation in a test
+    pub fn structurally_eq(&self, other: &Tree<T>) -> bool {
+        self.structurally_eq_recursive(self.root, other, other.root)
+    }
+
+    fn structurally_eq_recursive(&self, index: Option<usize>, other: &Tree<T>, other_index: Option<usize>) -> bool {
+        match (index, other_index) {
+            (Some(i), Some(j)) => {
+                self.value(i) == other.value(j)
+                    && self.color[i] == other.color[j]
+                    && self.structurally_eq_recursive(self.edge_list[i][1], other, other.edge_list[j][1])
+                    && self.structurally_eq_recursive(self.edge_list[i][2], other, other.edge_list[j][2])
+            }
+            (None, None) => true,
+            _ => false, // one side has a child the other doesn't
+        }
+    }
+
+    /// Checks that an in order walk of the tree visits strictly increasing values. This only
+    /// looks at the ordering of the data and not the colors, so it is a cheaper check than
+    /// `is_valid_red_black` for catching ordering corruption
+    pub fn is_bst(&self) -> bool {
+        let mut prev: Option<&T> = None;
+        self.is_bst_recursive(self.root, &mut prev)
+    }
+
+    // Walks the branch in order, checking each element against the one visited before it
+    fn is_bst_recursive<'a>(&'a self, index: Option<usize>, prev: &mut Option<&'a T>) -> bool {
+        match index {
+            Some(i) => {
+                if !self.is_bst_recursive(self.edge_list[i][1], prev) {
+                    return false;
+                }
+                let d = self.value(i);
+                if let Some(p) = *prev {
+                    if p.partial_cmp(d) != Some(Ordering::Less) {
+                        return false;
+                    }
+                }
+                *prev = Some(d);
+                self.is_bst_recursive(self.edge_list[i][2], prev)
+            }
+            None => true,
+        }
+    }
+
+    /// Gathers the length, height, black height, number of red nodes and number of free slots
+    /// in a single walk of the tree, e.g. for periodically logging a long lived tree
+    pub fn stats(&self) -> TreeStats {
+        let mut stats = TreeStats { len: 0, height: 0, black_height: None, red_nodes: 0, free_slots: self.empty.len() };
+        let (height, black_height) = self.stats_recursive(self.root, &mut stats);
+        stats.height = height;
+        stats.black_height = if self.is_red(self.root) { None } else { black_height };
+        stats
+    }
+
+    // Counts the nodes of a branch into stats and returns its height and black height
+    fn stats_recursive(&self, index: Option<usize>, stats: &mut TreeStats) -> (usize, Option<usize>) {
+        match index {
+            Some(idx) => {
+                let left = self.edge_list[idx][1];
+                let right = self.edge_list[idx][2];
+                stats.len += 1;
+                if self.color[idx] {
+                    stats.red_nodes += 1;
+                }
+                let (left_height, left_black) = self.stats_recursive(left, stats);
+                let (right_height, right_black) = self.stats_recursive(right, stats);
+                let red_red = self.color[idx] && (self.is_red(left) || self.is_red(right));
+                let black_height = match (left_black, right_black) {
+                    (Some(l), Some(r)) if l == r && !red_red => Some(l + if self.color[idx] { 0 } else { 1 }),
+                    _ => None,
+                };
+                (1 + left_height.max(right_height), black_height)
+            }
+            None => (0, Some(0)),
+        }
+    }
+
+    // Number of black nodes on every path from index down to a leaf, where None branches count
+    // as black. Returns None if a red node has a red child or two paths disagree
+    fn black_height(&self, index: Option<usize>) -> Option<usize> {
+        match index {
+            Some(idx) => {
+                let left = self.edge_list[idx][1];
+                let right = self.edge_list[idx][2];
+                if self.color[idx] && (self.is_red(left) || self.is_red(right)) { // red-red violation
+                    return None;
+                }
+                let left_height = self.black_height(left)?;
+                let right_height = self.black_height(right)?;
+                if left_height != right_height {
+                    return None;
+                }
+                Some(left_height + if self.color[idx] { 0 } else { 1 })
+            }
+            None => Some(1),
+        }
+    }
+
+    /// Debugging check that every node reachable from the root is pointed back to by its
+    /// children, i.e. `edge_list[child][0]` is the node the child hangs off of (and the root has
+    /// no parent). Returns `Err(index)` with the first node whose parent pointer is wrong
+    pub fn validate_parent_pointers(&self) -> Result<(), usize> {
+        match self.root {
+            Some(r) => {
+                if self.edge_list[r][0].is_some() {
+                    return Err(r);
+                }
+                self.validate_parent_recursive(r)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn validate_parent_recursive(&self, index: usize) -> Result<(), usize> {
+        for child in [self.edge_list[index][1], self.edge_list[index][2]].into_iter().flatten() {
+            if self.edge_list[child][0] != Some(index) {
+                return Err(child);
+            }
+            self.validate_parent_recursive(child)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the depth of the node holding value, where the root has a depth of 0.
+    /// Returns None if the value is not in the tree.
+    pub fn depth_of(&self, value: &T) -> Option<usize> {
+        let mut depth: usize = 0;
+        let mut index = self.root;
+        while let Some(idx) = index {
+            let d = self.value(idx);
+            if d == value { // Item found
+                return Some(depth);
+            } else if d > value { // check left
+                index = self.edge_list[idx][1];
+            } else { // check right
+                index = self.edge_list[idx][2];
+            }
+            depth += 1;
+        }
+        None
+    }
+
+    /// Returns the color of the node holding value, or None if the value is not in the tree.
+    pub fn color_of(&self, value: &T) -> Option<Color> {
+        self.contains_recursive(self.root, value).map(|idx| Color::from(self.color[idx]))
+    }
+
+    /// Iterates over the elements in ascending order. This is the way to traverse the tree, the
+    /// iterator follows the edges from one element to the next so nothing is allocated
+    pub fn iter(&self) -> Iter<'_, T> {
+        let front = self.root.map(|r| self.get_in_order_successor(r));
+        let back = self.root.map(|r| self.get_in_order_predecessor(r));
+        Iter { tree: self, front, back, remaining: self.len }
+    }
+
+    /// Iterates in ascending order starting from the first element greater than or equal to key.
+    /// The start is found with a single descent and key doesn't have to be in the tree
+    pub fn iter_from(&self, key: &T) -> Iter<'_, T> {
+        let front = self.lower_bound_index(Bound::Included(key));
+        let back = self.root.map(|r| self.get_in_order_predecessor(r));
+        Iter { tree: self, front, back, remaining: self.len - self.rank(key) }
+    }
+
+    /// Iterates in descending order starting from the last element less than or equal to key.
+    /// The mirror of `iter_from`
+    pub fn iter_back_from(&self, key: &T) -> impl Iterator<Item = &T> {
+        let front = self.root.map(|r| self.get_in_order_successor(r));
+        let back = self.upper_bound_index(Bound::Included(key));
+        Iter { tree: self, front, back, remaining: self.upper_bound_position(key) }.rev()
+    }
+
+    /// Iterates over the elements that are in self, other or both, in ascending order. An element
+    /// in both trees is yielded once, taken from self. The two trees are merged lazily as the
+    /// iterator advances, so nothing is cloned or allocated
+    pub fn union<'a>(&'a self, other: &'a Tree<T>) -> Union<'a, T> {
+        Union { a: self.iter().peekable(), b: other.iter().peekable() }
+    }
+
+    /// Iterates over the elements that are in both self and other, in ascending order. Each
+    /// element is taken from self. Trees of similar size are merged in O(n + m). When one is more
+    /// than LOPSIDED_RATIO times the size of the other, the small one is walked instead and each
+    /// of its elements looked up in the large one, which is O(small * log(large))
+    pub fn intersection<'a>(&'a self, other: &'a Tree<T>) -> Intersection<'a, T> {
+        let self_is_small = self.len <= other.len;
+        let (small, large) = if self_is_small { (self, other) } else { (other, self) };
+        if small.len * LOPSIDED_RATIO < large.len {
+            Intersection { inner: SetWalk::Search { small: small.iter(), large }, from_large: !self_is_small }
+        } else {
+            Intersection { inner: SetWalk::Stitch { a: self.iter(), b: other.iter().peekable() }, from_large: false }
+        }
+    }
+
+    /// Iterates over the elements of self that are not in other, in ascending order. Like
+    /// `intersection`, the trees are walked side by side unless self is much smaller than other,
+    /// in which case each element of self is looked up in other instead
+    pub fn difference<'a>(&'a self, other: &'a Tree<T>) -> Difference<'a, T> {
+        if self.len * LOPSIDED_RATIO < other.len {
+            Difference { inner: SetWalk::Search { small: self.iter(), large: other } }
+        } else {
+            Difference { inner: SetWalk::Stitch { a: self.iter(), b: other.iter().peekable() } }
+        }
+    }
+
+    /// Iterates over the elements that are in exactly one of self and other, in ascending order.
+    /// A single lazy pass over both trees, elements found on both sides are skipped
+    pub fn symmetric_difference<'a>(&'a self, other: &'a Tree<T>) -> SymmetricDifference<'a, T> {
+        SymmetricDifference { a: self.iter().peekable(), b: other.iter().peekable() }
+    }
+
+    /// Returns true if every element of self is also in other. Stops at the first element that
+    /// isn't, and a tree larger than other is never a subset so that case returns straight away
+    pub fn is_subset(&self, other: &Tree<T>) -> bool {
+        self.len <= other.len && self.difference(other).next().is_none()
+    }
+
+    /// Returns true if every element of other is also in self
+    pub fn is_superset(&self, other: &Tree<T>) -> bool {
+        other.is_subset(self)
+    }
+
+    /// Returns true if self and other have no elements in common. Stops at the first shared one
+    pub fn is_disjoint(&self, other: &Tree<T>) -> bool {
+        self.intersection(other).next().is_none()
+    }
+
+    /// Walks self and other together in ascending order, yielding every element of both and
+    /// saying which side it came from. Elements equal on both sides come out as one `Both`
+    /// pair, so matched and one-sided entries can be reconciled in a single pass
+    pub fn merge<'a>(&'a self, other: &'a Tree<T>) -> Merge<'a, T> {
+        Merge { a: self.iter().peekable(), b: other.iter().peekable() }
+    }
+
+    /// Iterates over the nodes in ascending order, yielding each value along with its depth,
+    /// color and which side of its parent it hangs on. Meant for pretty printers and debugging,
+    /// finding the depth climbs to the root so a full walk is O(n log n)
+    pub fn iter_annotated(&self) -> impl Iterator<Item = NodeInfo<'_, T>> {
+        let first = self.root.map(|r| self.get_in_order_successor(r));
+        std::iter::successors(first, |&idx| self.next_index(idx)).map(|idx| {
+            let position = match self.edge_list[idx][0] {
+                None => NodePosition::Root,
+                Some(p) if self.edge_list[p][1] == Some(idx) => NodePosition::LeftChild,
+                Some(_) => NodePosition::RightChild,
+            };
+            let depth = std::iter::successors(self.edge_list[idx][0], |&p| self.edge_list[p][0]).count();
+            NodeInfo { value: self.value(idx), depth, color: Color::from(self.color[idx]), position }
+        })
+    }
+
+    /// Iterates lazily over the leaves, the elements with no children, in ascending order
+    pub fn leaves(&self) -> impl Iterator<Item = &T> {
+        let first = self.root.map(|r| self.get_in_order_successor(r));
+        std::iter::successors(first, |&idx| self.next_index(idx))
+            .filter(|&idx| self.edge_list[idx][1].is_none() && self.edge_list[idx][2].is_none())
+            .map(|idx| self.value(idx))
+    }
+
+    /// Iterates over the elements in pre order (node, left branch, right branch), the same order
+    /// as `pre_order`. Follows the edges lazily without allocating
+    pub fn iter_pre_order(&self) -> PreOrder<'_, T> {
+        PreOrder { tree: self, next: self.root, remaining: self.len }
+    }
+
+    /// Iterates over the elements in post order (left branch, right branch, node), the same
+    /// order as `post_order`. Follows the edges lazily without allocating
+    pub fn iter_post_order(&self) -> PostOrder<'_, T> {
+        PostOrder { tree: self, next: self.root.map(|r| self.post_order_first(r)), remaining: self.len }
+    }
+
+    /// Iterates over the elements breadth first, from the root down one level at a time and left
+    /// to right within a level. The nodes still to visit are kept in a queue, which never holds
+    /// more than two levels of the tree
+    pub fn iter_level_order(&self) -> LevelOrder<'_, T> {
+        let mut queue: VecDeque<usize> = VecDeque::new();
+        queue.extend(self.root);
+        LevelOrder { tree: self, queue, remaining: self.len }
+    }
+
+    /// Clones every element into a vector in sorted order. The vector is allocated at its full
+    /// length up front. Use `iter().collect()` for a vector of references instead
+    pub fn to_vec(&self) -> Vec<T>
+    where
+        T: Clone,
+    {
+        let mut v: Vec<T> = Vec::with_capacity(self.len);
+        v.extend(self.iter().cloned());
+        v
+    }
+
+    /// Consumes the tree and moves its elements into a vector in sorted order, without cloning
+    /// any of them
+    pub fn into_sorted_vec(self) -> Vec<T> {
+        let mut v: Vec<T> = Vec::with_capacity(self.len);
+        v.extend(self);
+        v
+    }
+
+    /// Traverses the tree in the given order. Lets the order be picked at runtime, e.g.
+    /// `tree.traverse(order).collect::<Vec<_>>()`. Each order is walked lazily by its own
+    /// iterator, so taking the first few elements doesn't visit the rest of the tree
+    pub fn traverse(&self, order: TraversalOrder) -> Traverse<'_, T> {
+        let inner = match order {
+            TraversalOrder::In => TraverseInner::In(self.iter()),
+            TraversalOrder::Pre => TraverseInner::Pre(self.iter_pre_order()),
+            TraversalOrder::Post => TraverseInner::Post(self.iter_post_order()),
+            TraversalOrder::Level => TraverseInner::Level(self.iter_level_order()),
+        };
+        Traverse { inner }
+    }
+
+    /// Calls f on every element in ascending order. Recurses down the tree, so nothing is
+    /// allocated on the heap
+    pub fn for_each_in_order<F: FnMut(&T)>(&self, f: F) {
+        let mut f = f;
+        let _ = self.visit_recursive(self.root, TraversalOrder::In, &mut |d| {
+            f(d);
+            ControlFlow::Continue(())
+        });
+    }
+
+    /// Calls f on every element in pre order, without allocating
+    pub fn for_each_pre_order<F: FnMut(&T)>(&self, f: F) {
+        let mut f = f;
+        let _ = self.visit_recursive(self.root, TraversalOrder::Pre, &mut |d| {
+            f(d);
+            ControlFlow::Continue(())
+        });
+    }
+
+    /// Calls f on every element in post order, without allocating
+    pub fn for_each_post_order<F: FnMut(&T)>(&self, f: F) {
+        let mut f = f;
+        let _ = self.visit_recursive(self.root, TraversalOrder::Post, &mut |d| {
+            f(d);
+            ControlFlow::Continue(())
+        });
+    }
+
+    /// Calls f on the elements in ascending order until it returns `ControlFlow::Break`, e.g. to
+    /// stop at the first match. Returns `Break` if the walk was stopped early
+    pub fn try_for_each_in_order<F: FnMut(&T) -> ControlFlow<()>>(&self, f: F) -> ControlFlow<()> {
+        let mut f = f;
+        self.visit_recursive(self.root, TraversalOrder::In, &mut f)
+    }
+
+    // Calls f on every element of the branch at index in the given depth first order, stopping
+    // as soon as f breaks
+    fn visit_recursive<F: FnMut(&T) -> ControlFlow<()>>(&self, index: Option<usize>, order: TraversalOrder, f: &mut F) -> ControlFlow<()> {
+        if let Some(i) = index {
+            if order == TraversalOrder::Pre {
+                f(self.value(i))?;
+            }
+            self.visit_recursive(self.edge_list[i][1], order, f)?;
+            if order == TraversalOrder::In {
+                f(self.value(i))?;
+            }
+            self.visit_recursive(self.edge_list[i][2], order, f)?;
+            if order == TraversalOrder::Post {
+                f(self.value(i))?;
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// in_order traverses the tree and returns a list of the nodes in depth first order.
+    /// Kept for compatibility, `iter` gives the same elements without allocating
+    pub fn in_order(&self) -> LinkedList<&T> {
+        self.traverse(TraversalOrder::In).collect()
+    }
+
+    /// Pre order traversal of the tree
+    pub fn pre_order(&self) -> LinkedList<&T> {
+        self.traverse(TraversalOrder::Pre).collect()
+    }
+
+    /// Post order traversal of the tree
+    pub fn post_order(&self) -> LinkedList<&T> {
+        self.traverse(TraversalOrder::Post).collect()
+    }
+}
+
+/// Prints the elements in sorted order like a set, e.g. `{1, 2, 3}`. The alternate form `{:#?}`
+/// prints the structure instead, one node per line with its color, indented under its parent:
+/// ```text
+/// 2 (black)
+///     L: 1 (red)
+///     R: 3 (red)
+/// ```
+impl<T: std::cmp::PartialOrd + fmt::Debug> fmt::Debug for Tree<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if f.alternate() {
+            match self.root {
+                Some(r) => self.fmt_structure(f, r, "", 0),
+                None => write!(f, "(empty)"),
+            }
+        } else {
+            f.debug_set().entries(self.iter()).finish()
+        }
+    }
+}
+
+impl<T: std::cmp::PartialOrd + fmt::Debug> Tree<T> {
+    // Writes the branch at index for the alternate Debug form. Only nodes reachable from the root
+    // are visited, so free slots never show up
+    fn fmt_structure(&self, f: &mut fmt::Formatter<'_>, index: usize, label: &str, depth: usize) -> fmt::Result {
+        let color = if self.color[index] { "red" } else { "black" };
+        if depth > 0 { // every line but the root's starts on a new one, so nothing trails the last
+            writeln!(f)?;
+        }
+        write!(f, "{:indent$}{}{:?} ({})", "", label, self.value(index), color, indent = depth * 4)?;
+        if let Some(lc) = self.edge_list[index][1] {
+            self.fmt_structure(f, lc, "L: ", depth + 1)?;
+        }
+        if let Some(rc) = self.edge_list[index][2] {
+            self.fmt_structure(f, rc, "R: ", depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+// Bytes every snapshot starts with, followed by the format version as a little endian u32
+const SNAPSHOT_MAGIC: [u8; 4] = *b"RBTV";
+const SNAPSHOT_VERSION: u32 = 1;
+
+impl<T: std::cmp::PartialOrd + SnapshotValue> Tree<T> {
+    /// Writes the tree to w as it sits in memory, slot for slot, so `read_snapshot` gets back a
+    /// tree with the same shape, colors, indices and free slots without rebalancing anything.
+    /// The format is a magic number and version, the slot count, the root and the free stack,
+    /// then every slot: a flags byte (live, red), and for live slots the three edges and value
+    pub fn write_snapshot<W: Write>(&self, mut w: W) -> io::Result<()> {
+        w.write_all(&SNAPSHOT_MAGIC)?;
+        SNAPSHOT_VERSION.write_value(&mut w)?;
+        self.graph.len().write_value(&mut w)?;
+        snapshot::write_index(&mut w, self.root)?;
+        self.empty.len().write_value(&mut w)?;
+        for &i in self.empty.iter() {
+            i.write_value(&mut w)?;
+        }
+        for (i, node) in self.graph.iter().enumerate() {
+            match node.data.as_deref() {
+                Some(d) => {
+                    let flags: u8 = if self.color[i] { 0b11 } else { 0b01 };
+                    flags.write_value(&mut w)?;
+                    for &edge in self.edge_list[i].iter() {
+                        snapshot::write_index(&mut w, edge)?;
+                    }
+                    d.write_value(&mut w)?;
+                }
+                None => 0u8.write_value(&mut w)?,
+            }
+        }
+        w.flush()
+    }
+
+    /// Reads a tree written by `write_snapshot`. Everything read is checked before the tree is
+    /// handed back: indices must be in range, parent and child pointers must agree, every live
+    /// slot must hang off the root, the free stack must list exactly the free slots, and the
+    /// tree must be a valid red-black tree in order. Anything else fails with `InvalidData`
+    /// rather than producing a tree that would panic later
+    pub fn read_snapshot<R: Read>(mut r: R) -> io::Result<Tree<T>> {
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if magic != SNAPSHOT_MAGIC {
+            return Err(snapshot::invalid("not a tree snapshot"));
+        }
+        if u32::read_value(&mut r)? != SNAPSHOT_VERSION {
+            return Err(snapshot::invalid("unsupported snapshot version"));
+        }
+        let slots = usize::read_value(&mut r)?;
+        // The counts come from the file, so only a bounded amount is reserved up front
+        let mut tree: Tree<T> = Tree::with_capacity(slots.min(4096));
+        tree.root = snapshot::read_index(&mut r, slots)?;
+        let free = usize::read_value(&mut r)?;
+        for _ in 0..free {
+            match snapshot::read_index(&mut r, slots)? {
+                Some(i) => tree.empty.push_back(i),
+                None => return Err(snapshot::invalid("free slot is not an index")),
+            }
+        }
+        for _ in 0..slots {
+            match u8::read_value(&mut r)? {
+                0 => {
+                    tree.graph.push(Node { data: None });
+                    tree.edge_list.push(vec![None, None, None]);
+                    tree.color.push(false);
+                }
+                flags @ (0b01 | 0b11) => {
+                    let mut edges = vec![None, None, None];
+                    for edge in edges.iter_mut() {
+                        *edge = snapshot::read_index(&mut r, slots)?;
+                    }
+                    tree.graph.push(Node { data: Some(Box::new(T::read_value(&mut r)?)) });
+                    tree.edge_list.push(edges);
+                    tree.color.push(flags == 0b11);
+                    tree.len += 1;
+                }
+                _ => return Err(snapshot::invalid("unknown slot flags")),
+            }
+        }
+        tree.subtree_size = vec![0; slots];
+        tree.validate_snapshot()?;
+        Ok(tree)
+    }
+
+    // Checks a tree fresh out of read_snapshot and fills in its subtree sizes. The pointer checks
+    // run first and without recursion, so the recursive checks at the end only ever see a real
+    // tree that is no deeper than a red-black tree of its size can be
+    fn validate_snapshot(&mut self) -> io::Result<()> {
+        let slots = self.graph.len();
+        let is_live = |tree: &Tree<T>, i: usize| tree.graph[i].data.is_some();
+        let mut listed = vec![false; slots];
+        for &i in self.empty.iter() {
+            if is_live(self, i) || listed[i] {
+                return Err(snapshot::invalid("free stack does not match the free slots"));
+            }
+            listed[i] = true;
+        }
+        if self.empty.len() + self.len != slots {
+            return Err(snapshot::invalid("free stack does not match the free slots"));
+        }
+        for i in (0..slots).filter(|&i| is_live(self, i)) {
+            let [parent, left, right] = [self.edge_list[i][0], self.edge_list[i][1], self.edge_list[i][2]];
+            if left.is_some() && left == right {
+                return Err(snapshot::invalid("node has the same left and right child"));
+            }
+            for child in [left, right].into_iter().flatten() {
+                if !is_live(self, child) || self.edge_list[child][0] != Some(i) {
+                    return Err(snapshot::invalid("child does not point back to its parent"));
+                }
+            }
+            let attached = match parent {
+                Some(p) => is_live(self, p) && (self.edge_list[p][1] == Some(i) || self.edge_list[p][2] == Some(i)),
+                None => self.root == Some(i),
+            };
+            if !attached {
+                return Err(snapshot::invalid("parent does not point to its child"));
+            }
+        }
+        if self.root.is_some_and(|r| !is_live(self, r) || self.edge_list[r][0].is_some()) || (self.root.is_none() && self.len > 0) {
+            return Err(snapshot::invalid("root does not match the live slots"));
+        }
+        // Walk down from the root. Every node has a single parent that points to it, so nothing
+        // is visited twice, and a live slot that is never reached sits on a detached cycle
+        let max_depth = 2 * (usize::BITS - self.len.leading_zeros()) as usize;
+        let mut order = Vec::with_capacity(self.len);
+        let mut stack: Vec<(usize, usize)> = self.root.map(|r| (r, 0)).into_iter().collect();
+        while let Some((i, depth)) = stack.pop() {
+            if depth > max_depth {
+                return Err(snapshot::invalid("tree is too deep to be balanced"));
+            }
+            order.push(i);
+            for child in [self.edge_list[i][1], self.edge_list[i][2]].into_iter().flatten() {
+                stack.push((child, depth + 1));
+            }
+        }
+        if order.len() != self.len {
+            return Err(snapshot::invalid("live slot is not reachable from the root"));
+        }
+        // Children come after their parent in order, so going backwards sizes them first
+        for &i in order.iter().rev() {
+            let children: usize = [self.edge_list[i][1], self.edge_list[i][2]].into_iter().flatten().map(|c| self.subtree_size[c]).sum();
+            self.subtree_size[i] = 1 + children;
+        }
+        if !self.is_valid_red_black() {
+            return Err(snapshot::invalid("red-black properties do not hold"));
+        }
+        if !self.is_bst() {
+            return Err(snapshot::invalid("elements are not in order"));
+        }
+        Ok(())
+    }
+}
+
+/// Indexing is by sorted position, not insertion order: `tree[0]` is the smallest element and
+/// `tree[k]` the k-th smallest. Runs in O(log n) using the subtree sizes.
+/// Panics if the index is out of range, like `Vec`
+impl<T: std::cmp::PartialOrd> Index<usize> for Tree<T> {
+    type Output = T;
+
+    fn index(&self, index: usize) -> &T {
+        match self.select_index(index) {
+            Some(idx) => self.value(idx),
+            None => panic!("index out of bounds: the len is {} but the index is {}", self.len, index),
+        }
+    }
+}
+
+/// Two trees are equal when they hold equal elements, whatever order they were inserted in and
+/// however their nodes ended up arranged
+impl<T: std::cmp::PartialOrd> PartialEq for Tree<T> {
+    fn eq(&self, other: &Tree<T>) -> bool {
+        self.len == other.len && self.iter().eq(other.iter())
+    }
+}
+
+impl<T: std::cmp::PartialOrd + Eq> Eq for Tree<T> {}
+
+/// Trees are ordered like `BTreeSet`, by comparing their sorted elements lexicographically.
+/// A tree whose elements are a prefix of another's comes first, so the empty tree is the least
+impl<T: std::cmp::PartialOrd> PartialOrd for Tree<T> {
+    fn partial_cmp(&self, other: &Tree<T>) -> Option<Ordering> {
+        self.iter().partial_cmp(other.iter())
+    }
+}
+
+impl<T: Ord> Ord for Tree<T> {
+    fn cmp(&self, other: &Tree<T>) -> Ordering {
+        self.iter().cmp(other.iter())
+    }
+}
+
+// Hashes the length and then the elements in ascending order, the same things eq looks at
+impl<T: std::cmp::PartialOrd + Hash> Hash for Tree<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_usize(self.len);
+        for value in self.iter() {
+            value.hash(state);
+        }
+    }
+}
+
+impl<T> Default for Tree<T> {
+    fn default() -> Tree<T> {
+        Tree::new()
+    }
+}
+
+/// Cloning only copies the slots that hold an element. Slots freed by removals are left out and
+/// every node is stored at its position in sorted order, so the clone comes out compacted with
+/// the same shape and colors as the original and an empty free list
+impl<T: Clone> Clone for Tree<T> {
+    fn clone(&self) -> Tree<T> {
+        let mut tree = Tree {
+            graph: Vec::with_capacity(self.len),
+            edge_list: Vec::with_capacity(self.len),
+            empty: LinkedList::new(),
+            color: Vec::with_capacity(self.len),
+            subtree_size: Vec::with_capacity(self.len),
+            root: None,
+            len: 0,
+        };
+        tree.clone_from(self);
+        tree
+    }
+
+    // Copies source into the vectors already owned by self. Slots self already has keep their
+    // edge vectors and boxes, the values in them are overwritten with T::clone_from, so cloning
+    // into a tree at least as large as source doesn't allocate for the tree itself
+    fn clone_from(&mut self, source: &Tree<T>) {
+        // A node goes to the slot matching its rank in sorted order. Walking in order visits the
+        // slots front to back, and the rank of a neighbour follows from the rank of the node and
+        // the size of the branch between them, so no table of new indices is needed
+        let size = |e: Option<usize>| e.map_or(0, |i| source.subtree_size[i]);
+        self.empty.clear();
+        self.color.clear();
+        self.subtree_size.clear();
+        let mut next = source.root;
+        while let Some(c) = next.and_then(|i| source.edge_list[i][1]) { // start at the smallest
+            next = Some(c);
+        }
+        let mut rank: usize = 0;
+        while let Some(i) = next {
+            let [parent, left, right] = [0, 1, 2].map(|e| source.edge_list[i][e]);
+            let parent = parent.map(|p| {
+                if source.edge_list[p][1] == Some(i) {
+                    rank + 1 + size(right)
+                } else {
+                    rank - 1 - size(left)
+                }
+            });
+            let left = left.map(|c| rank - 1 - size(source.edge_list[c][2]));
+            let right = right.map(|c| rank + 1 + size(source.edge_list[c][1]));
+            let value = match source.graph[i].data.as_deref() {
+                Some(v) => v,
+                None => unreachable!(), // only live nodes are reachable from the root
+            };
+            match self.graph.get_mut(rank) {
+                Some(Node { data: Some(dst) }) => (**dst).clone_from(value),
+                Some(node) => node.data = Some(Box::new(value.clone())), // a free slot of self
+                None => self.graph.push(Node { data: Some(Box::new(value.clone())) }),
+            }
+            match self.edge_list.get_mut(rank) {
+                Some(edges) => edges.copy_from_slice(&[parent, left, right]),
+                None => self.edge_list.push(vec![parent, left, right]),
+            }
+            self.color.push(source.color[i]);
+            self.subtree_size.push(source.subtree_size[i]);
+            rank += 1;
+            next = step_in_order(&source.edge_list, i, true);
+        }
+        self.graph.truncate(rank);
+        self.edge_list.truncate(rank);
+        self.root = source.root.map(|r| size(source.edge_list[r][1]));
+        self.len = source.len;
+    }
+}
+
+/// Builds a tree from a vector of values. The values are sorted and deduplicated, then the tree
+/// is built with `Tree::from_sorted_iter` in O(n). When several values are equal the first one
+/// in the vector is kept, the same one `insert` would have kept
+impl<T: std::cmp::PartialOrd> From<Vec<T>> for Tree<T> {
+    fn from(values: Vec<T>) -> Tree<T> {
+        let mut values = values;
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal)); // stable, equal values keep their order
+        values.dedup_by(|later, earlier| later == earlier); // keeps the earlier of each run
+        Tree::from_sorted_iter(values)
+    }
+}
+
+/// Builds a tree from an array of values, e.g. `Tree::from([3, 1, 2])`. Same as `From<Vec<T>>`
+impl<T: std::cmp::PartialOrd, const N: usize> From<[T; N]> for Tree<T> {
+    fn from(values: [T; N]) -> Tree<T> {
+        Tree::from(Vec::from(values))
+    }
+}
+
+/// Builds a tree from a `BTreeSet` in O(n). The set already iterates in ascending order without
+/// repeats, so it goes straight into `Tree::from_sorted_iter`
+impl<T: Ord> From<BTreeSet<T>> for Tree<T> {
+    fn from(set: BTreeSet<T>) -> Tree<T> {
+        Tree::from_sorted_iter(set)
+    }
+}
+
+/// Moves the elements of a tree into a `BTreeSet`
+impl<T: Ord> From<Tree<T>> for BTreeSet<T> {
+    fn from(tree: Tree<T>) -> BTreeSet<T> {
+        tree.into_iter().collect()
+    }
+}
+
+/// Clones the elements of a tree into a `BTreeSet`, leaving the tree as it is
+impl<T: Ord + Clone> From<&Tree<T>> for BTreeSet<T> {
+    fn from(tree: &Tree<T>) -> BTreeSet<T> {
+        tree.iter().cloned().collect()
+    }
+}
+
+/// `&a | &b` clones the union of two trees into a new tree. The set operators all feed their
+/// iterator's ascending output to `Tree::from_sorted_iter`, so they run in O(n + m)
+impl<T: Ord + Clone> BitOr<&Tree<T>> for &Tree<T> {
+    type Output = Tree<T>;
+
+    fn bitor(self, rhs: &Tree<T>) -> Tree<T> {
+        Tree::from_sorted_iter(self.union(rhs).cloned())
+    }
+}
+
+/// `&a & &b` clones the intersection of two trees into a new tree
+impl<T: Ord + Clone> BitAnd<&Tree<T>> for &Tree<T> {
+    type Output = Tree<T>;
+
+    fn bitand(self, rhs: &Tree<T>) -> Tree<T> {
+        Tree::from_sorted_iter(self.intersection(rhs).cloned())
+    }
+}
+
+/// `&a - &b` clones the elements of a that are not in b into a new tree
+impl<T: Ord + Clone> Sub<&Tree<T>> for &Tree<T> {
+    type Output = Tree<T>;
+
+    fn sub(self, rhs: &Tree<T>) -> Tree<T> {
+        Tree::from_sorted_iter(self.difference(rhs).cloned())
+    }
+}
+
+/// `&a ^ &b` clones the elements in exactly one of the two trees into a new tree
+impl<T: Ord + Clone> BitXor<&Tree<T>> for &Tree<T> {
+    type Output = Tree<T>;
+
+    fn bitxor(self, rhs: &Tree<T>) -> Tree<T> {
+        Tree::from_sorted_iter(self.symmetric_difference(rhs).cloned())
+    }
+}
+
+/// Collects values into a tree, reserving space for the iterator's lower size hint up front.
+/// Duplicate values are dropped in the same way as `insert`
+impl<T: std::cmp::PartialOrd> FromIterator<T> for Tree<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Tree<T> {
+        let iter = iter.into_iter();
+        let mut tree = Tree::with_capacity(iter.size_hint().0);
+        for value in iter {
+            tree.insert(value);
+        }
+        tree
+    }
+}
+
+/// Inserts every value of the iterator, reserving space for its lower size hint first
+impl<T: std::cmp::PartialOrd> Extend<T> for Tree<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        self.reserve(iter.size_hint().0);
+        for value in iter {
+            self.insert(value);
+        }
+    }
+}
+
+/// Iterates over references to the elements in ascending order, same as `Tree::iter`
+impl<'a, T: std::cmp::PartialOrd> IntoIterator for &'a Tree<T> {
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+/// Iterator over the elements of a tree in ascending order, created by `Tree::iter`.
+/// It can be walked from both ends, `tree.iter().rev()` gives descending order
+pub struct Iter<'a, T> {
+    tree: &'a Tree<T>,
+    front: Option<usize>, // next element from the front
+    back: Option<usize>, // next element from the back
+    remaining: usize, // elements between front and back, stops the two ends crossing
+}
+
+impl<'a, T: std::cmp::PartialOrd> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let idx = self.front?;
+        self.front = self.tree.next_index(idx);
+        self.remaining -= 1;
+        Some(self.tree.value(idx))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, T: std::cmp::PartialOrd> DoubleEndedIterator for Iter<'a, T> {
+    fn next_back(&mut self) -> Option<&'a T> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let idx = self.back?;
+        self.back = self.tree.prev_index(idx);
+        self.remaining -= 1;
+        Some(self.tree.value(idx))
+    }
+}
+
+impl<T: std::cmp::PartialOrd> ExactSizeIterator for Iter<'_, T> {}
+
+impl<T: std::cmp::PartialOrd> FusedIterator for Iter<'_, T> {}
+
+/// Iterator over the elements of a tree in pre order, created by `Tree::iter_pre_order`
+pub struct PreOrder<'a, T> {
+    tree: &'a Tree<T>,
+    next: Option<usize>,
+    remaining: usize,
+}
+
+impl<'a, T: std::cmp::PartialOrd> Iterator for PreOrder<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        let idx = self.next?;
+        self.next = self.tree.pre_order_next(idx);
+        self.remaining -= 1;
+        Some(self.tree.value(idx))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<T: std::cmp::PartialOrd> ExactSizeIterator for PreOrder<'_, T> {}
+
+impl<T: std::cmp::PartialOrd> FusedIterator for PreOrder<'_, T> {}
+
+/// Iterator over the elements of a tree in post order, created by `Tree::iter_post_order`
+pub struct PostOrder<'a, T> {
+    tree: &'a Tree<T>,
+    next: Option<usize>,
+    remaining: usize,
+}
+
+impl<'a, T: std::cmp::PartialOrd> Iterator for PostOrder<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        let idx = self.next?;
+        self.next = self.tree.post_order_next(idx);
+        self.remaining -= 1;
+        Some(self.tree.value(idx))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<T: std::cmp::PartialOrd> ExactSizeIterator for PostOrder<'_, T> {}
+
+impl<T: std::cmp::PartialOrd> FusedIterator for PostOrder<'_, T> {}
+
+/// Iterator over the elements of a tree level by level, created by `Tree::iter_level_order`
+pub struct LevelOrder<'a, T> {
+    tree: &'a Tree<T>,
+    queue: VecDeque<usize>,
+    remaining: usize,
+}
+
+impl<'a, T: std::cmp::PartialOrd> Iterator for LevelOrder<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        let idx = self.queue.pop_front()?;
+        self.queue.extend(self.tree.edge_list[idx][1]);
+        self.queue.extend(self.tree.edge_list[idx][2]);
+        self.remaining -= 1;
+        Some(self.tree.value(idx))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<T: std::cmp::PartialOrd> ExactSizeIterator for LevelOrder<'_, T> {}
+
+impl<T: std::cmp::PartialOrd> FusedIterator for LevelOrder<'_, T> {}
+
+/// Iterator over the elements of a tree in the order picked at runtime, created by
+/// `Tree::traverse`
+pub struct Traverse<'a, T> {
+    inner: TraverseInner<'a, T>,
+}
+
+// One iterator per order, Traverse hands every call on to whichever one it holds
+enum TraverseInner<'a, T> {
+    In(Iter<'a, T>),
+    Pre(PreOrder<'a, T>),
+    Post(PostOrder<'a, T>),
+    Level(LevelOrder<'a, T>),
+}
+
+impl<'a, T: std::cmp::PartialOrd> Iterator for Traverse<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        match &mut self.inner {
+            TraverseInner::In(it) => it.next(),
+            TraverseInner::Pre(it) => it.next(),
+            TraverseInner::Post(it) => it.next(),
+            TraverseInner::Level(it) => it.next(),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match &self.inner {
+            TraverseInner::In(it) => it.size_hint(),
+            TraverseInner::Pre(it) => it.size_hint(),
+            TraverseInner::Post(it) => it.size_hint(),
+            TraverseInner::Level(it) => it.size_hint(),
+        }
+    }
+}
+
+impl<T: std::cmp::PartialOrd> ExactSizeIterator for Traverse<'_, T> {}
+
+impl<T: std::cmp::PartialOrd> FusedIterator for Traverse<'_, T> {}
+
+/// A position between two elements of a tree (or before the first or after the last one),
+/// created by `Tree::lower_bound` and `Tree::upper_bound`. The cursor can be moved back and
+/// forth one element at a time and peeks at the elements on either side of it
+pub struct Cursor<'a, T> {
+    tree: &'a Tree<T>,
+    next: Option<usize>, // element just after the cursor, None at the end
+}
+
+impl<'a, T: std::cmp::PartialOrd> Cursor<'a, T> {
+    /// Returns the element after the cursor without moving it, or None at the end
+    pub fn peek_next(&self) -> Option<&'a T> {
+        self.next.map(|idx| self.tree.value(idx))
+    }
+
+    /// Returns the element before the cursor without moving it, or None at the start
+    pub fn peek_prev(&self) -> Option<&'a T> {
+        self.prev().map(|idx| self.tree.value(idx))
+    }
+
+    /// Moves the cursor past the next element and returns it. At the end the cursor stays put
+    /// and None is returned
+    pub fn move_next(&mut self) -> Option<&'a T> {
+        let idx = self.next?;
+        self.next = self.tree.next_index(idx);
+        Some(self.tree.value(idx))
+    }
+
+    /// Moves the cursor back past the previous element and returns it. At the start the cursor
+    /// stays put and None is returned
+    pub fn move_prev(&mut self) -> Option<&'a T> {
+        let idx = self.prev()?;
+        self.next = Some(idx);
+        Some(self.tree.value(idx))
+    }
+
+    // Index of the element just before the cursor
+    fn prev(&self) -> Option<usize> {
+        self.tree.index_before(self.next)
+    }
+}
+
+/// A cursor that can also edit the tree at its position, created by `Tree::lower_bound_mut`.
+/// Like `Cursor` it sits in the gap between two elements. The element after the cursor is the
+/// current one, the one `remove_current` takes out
+pub struct CursorMut<'a, T> {
+    tree: &'a mut Tree<T>,
+    next: Option<usize>, // element just after the cursor, None at the end
+}
+
+impl<T: std::cmp::PartialOrd> CursorMut<'_, T> {
+    /// Returns the element after the cursor without moving it, or None at the end
+    pub fn peek_next(&self) -> Option<&T> {
+        self.next.map(|idx| self.tree.value(idx))
+    }
+
+    /// Returns the element before the cursor without moving it, or None at the start
+    pub fn peek_prev(&self) -> Option<&T> {
+        self.tree.index_before(self.next).map(|idx| self.tree.value(idx))
+    }
+
+    /// Moves the cursor past the next element and returns it. At the end the cursor stays put
+    /// and None is returned
+    pub fn move_next(&mut self) -> Option<&T> {
+        let idx = self.next?;
+        self.next = self.tree.next_index(idx);
+        Some(self.tree.value(idx))
+    }
+
+    /// Moves the cursor back past the previous element and returns it. At the start the cursor
+    /// stays put and None is returned
+    pub fn move_prev(&mut self) -> Option<&T> {
+        let idx = self.tree.index_before(self.next)?;
+        self.next = Some(idx);
+        Some(self.tree.value(idx))
+    }
+
+    /// Removes the element after the cursor and returns it, or None at the end. The cursor
+    /// stays in the same gap, so the element that followed the removed one is now current
+    pub fn remove_current(&mut self) -> Option<T> {
+        let idx = self.next?;
+        self.next = self.tree.next_index(idx); // removing idx only relinks nodes, this index stays valid
+        Some(self.tree.remove_index(idx))
+    }
+
+    /// Inserts value into the gap so that it ends up before the cursor, the cursor stays in
+    /// front of the current element. value must sort strictly between the elements on either
+    /// side of the cursor, otherwise it is handed back as `Err(value)` and nothing changes
+    pub fn insert_before(&mut self, value: T) -> Result<(), T> {
+        self.insert_in_gap(value).map(|_| ())
+    }
+
+    /// Inserts value into the gap so that it ends up after the cursor and becomes the current
+    /// element. value must sort strictly between the elements on either side of the cursor,
+    /// otherwise it is handed back as `Err(value)` and nothing changes
+    pub fn insert_after(&mut self, value: T) -> Result<(), T> {
+        let idx = self.insert_in_gap(value)?;
+        self.next = Some(idx);
+        Ok(())
+    }
+
+    // Attaches value at the cursor's gap without descending from the root and returns its index.
+    // The gap is the left slot of the next element if that's free, otherwise the right slot of
+    // the previous element, which then is the rightmost node of next's left branch
+    fn insert_in_gap(&mut self, value: T) -> Result<usize, T> {
+        let prev = self.tree.index_before(self.next);
+        let after_prev = prev.is_none_or(|p| self.tree.value(p) < &value);
+        let before_next = self.next.is_none_or(|n| &value < self.tree.value(n));
+        if !(after_prev && before_next) {
+            return Err(value);
+        }
+        let (parent, is_left) = match self.next {
+            Some(n) if self.tree.edge_list[n][1].is_none() => (Some(n), true),
+            _ => (prev, false),
+        };
+        Ok(self.tree.insert_at(parent, is_left, value))
+    }
+}
+
+/// Consumes the tree, yielding its elements by value in ascending order
+impl<T: std::cmp::PartialOrd> IntoIterator for Tree<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        let front = self.root.map(|r| self.get_in_order_successor(r));
+        let back = self.root.map(|r| self.get_in_order_predecessor(r));
+        let remaining = self.len;
+        IntoIter { tree: self, front, back, remaining }
+    }
+}
+
+/// Owning iterator over the elements of a tree in sorted order, created by `Tree::into_iter`.
+/// Values are moved out of their slots as they are yielded, whatever is left when the iterator
+/// is dropped goes down with the tree
+pub struct IntoIter<T> {
+    tree: Tree<T>,
+    front: Option<usize>, // next element from the front
+    back: Option<usize>, // next element from the back
+    remaining: usize, // elements between front and back, stops the two ends crossing
+}
+
+impl<T: std::cmp::PartialOrd> Iterator for IntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let idx = self.front?;
+        // Only the data is taken, the edges stay intact so the walk can carry on from idx
+        self.front = self.tree.next_index(idx);
+        self.remaining -= 1;
+        match self.tree.graph[idx].data.take() {
+            Some(data) => Some(*data),
+            None => unreachable!(), // every element between front and back still holds data
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<T: std::cmp::PartialOrd> DoubleEndedIterator for IntoIter<T> {
+    fn next_back(&mut self) -> Option<T> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let idx = self.back?;
+        self.back = self.tree.prev_index(idx);
+        self.remaining -= 1;
+        match self.tree.graph[idx].data.take() {
+            Some(data) => Some(*data),
+            None => unreachable!(), // every element between front and back still holds data
+        }
+    }
+}
+
+impl<T: std::cmp::PartialOrd> ExactSizeIterator for IntoIter<T> {}
+
+impl<T: std::cmp::PartialOrd> FusedIterator for IntoIter<T> {}
+
+/// Iterator over the elements of a tree within a range, created by `Tree::range`
+pub struct Range<'a, T> {
+    tree: &'a Tree<T>,
+    front: Option<usize>, // next element from the front
+    back: Option<usize>, // next element from the back
+    remaining: usize, // elements in range not yet handed out, counted up front
+}
+
+impl<'a, T: std::cmp::PartialOrd> Iterator for Range<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let idx = self.front?;
+        self.front = self.tree.next_index(idx);
+        self.remaining -= 1;
+        Some(self.tree.value(idx))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, T: std::cmp::PartialOrd> DoubleEndedIterator for Range<'a, T> {
+    fn next_back(&mut self) -> Option<&'a T> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let idx = self.back?;
+        self.back = self.tree.prev_index(idx);
+        self.remaining -= 1;
+        Some(self.tree.value(idx))
+    }
+}
+
+impl<T: std::cmp::PartialOrd> ExactSizeIterator for Range<'_, T> {}
+
+impl<T: std::cmp::PartialOrd> FusedIterator for Range<'_, T> {}
+
+/// Iterator that removes and yields the elements matching a predicate, created by
+/// `Tree::extract_if`
+pub struct ExtractIf<'a, T, F> {
+    tree: &'a mut Tree<T>,
+    next: Option<usize>, // next element to hand to the predicate
+    pred: F,
+}
+
+impl<T: std::cmp::PartialOrd, F: FnMut(&T) -> bool> Iterator for ExtractIf<'_, T, F> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        while let Some(idx) = self.next {
+            // Removing idx only relinks nodes, so the index of the element after it stays valid
+            self.next = self.tree.next_index(idx);
+            if (self.pred)(self.tree.value(idx)) {
+                return Some(self.tree.remove_index(idx));
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self.next {
+            Some(_) => (0, Some(self.tree.len)), // anything still in the tree might match
+            None => (0, Some(0)),
+        }
+    }
+}
+
+impl<T: std::cmp::PartialOrd, F: FnMut(&T) -> bool> FusedIterator for ExtractIf<'_, T, F> {}
+
+/// Iterator that moves every element out of a tree in ascending order, created by `Tree::drain`
+pub struct Drain<'a, T: std::cmp::PartialOrd> {
+    tree: &'a mut Tree<T>,
+    next: Option<usize>, // next element to move out
+    remaining: usize,
+}
+
+impl<T: std::cmp::PartialOrd> Iterator for Drain<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        let idx = self.next?;
+        // The edges are still intact, only the data is taken, so the walk can carry on from idx
+        self.next = self.tree.next_index(idx);
+        self.remaining -= 1;
+        match self.tree.graph[idx].data.take() {
+            Some(data) => Some(*data),
+            None => unreachable!(), // every linked node holds data
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<T: std::cmp::PartialOrd> ExactSizeIterator for Drain<'_, T> {}
+
+impl<T: std::cmp::PartialOrd> FusedIterator for Drain<'_, T> {}
+
+impl<T: std::cmp::PartialOrd> Drop for Drain<'_, T> {
+    fn drop(&mut self) {
+        self.tree.clear(); // drops whatever wasn't yielded
+    }
+}
+
+/// Iterator over the elements of two trees merged into one ascending sequence without repeats,
+/// created by `Tree::union`
+pub struct Union<'a, T: std::cmp::PartialOrd> {
+    a: Peekable<Iter<'a, T>>,
+    b: Peekable<Iter<'a, T>>,
+}
+
+impl<'a, T: std::cmp::PartialOrd> Iterator for Union<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        let order = match (self.a.peek(), self.b.peek()) {
+            (Some(x), Some(y)) => x.partial_cmp(y),
+            (Some(_), None) => Some(Ordering::Less),
+            (None, _) => Some(Ordering::Greater),
+        };
+        match order {
+            Some(Ordering::Greater) => self.b.next(),
+            Some(Ordering::Equal) => {
+                self.b.next(); // the same element from the other side
+                self.a.next()
+            }
+            _ => self.a.next(),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (a, b) = (self.a.len(), self.b.len());
+        (a.max(b), Some(a + b)) // somewhere between fully overlapping and disjoint
+    }
+}
+
+impl<T: std::cmp::PartialOrd> FusedIterator for Union<'_, T> {}
+
+/// Iterator over the elements two trees have in common, in ascending order, created by
+/// `Tree::intersection`
+pub struct Intersection<'a, T: std::cmp::PartialOrd> {
+    inner: SetWalk<'a, T>,
+    from_large: bool, // self is the large side of a Search, so matches are taken from large
+}
+
+// How a set operation gets through its two trees
+enum SetWalk<'a, T: std::cmp::PartialOrd> {
+    Stitch { a: Iter<'a, T>, b: Peekable<Iter<'a, T>> }, // both walked side by side
+    Search { small: Iter<'a, T>, large: &'a Tree<T> }, // small walked, each element looked up in large
+}
+
+impl<'a, T: std::cmp::PartialOrd> Iterator for Intersection<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        match &mut self.inner {
+            SetWalk::Stitch { a, b } => {
+                let mut x = a.next()?;
+                let mut y = b.next()?;
+                loop {
+                    match x.partial_cmp(y) {
+                        Some(Ordering::Equal) => return Some(x),
+                        Some(Ordering::Greater) => y = b.next()?,
+                        _ => x = a.next()?,
+                    }
+                }
+            }
+            SetWalk::Search { small, large } => {
+                let from_large = self.from_large;
+                small.find_map(|x| large.get(x).map(|y| if from_large { y } else { x }))
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match &self.inner {
+            SetWalk::Stitch { a, b } => (0, Some(a.len().min(b.len()))),
+            SetWalk::Search { small, .. } => (0, Some(small.len())),
+        }
+    }
+}
+
+impl<T: std::cmp::PartialOrd> FusedIterator for Intersection<'_, T> {}
+
+/// Iterator over the elements of one tree that are missing from another, in ascending order,
+/// created by `Tree::difference`
+pub struct Difference<'a, T: std::cmp::PartialOrd> {
+    inner: SetWalk<'a, T>,
+}
+
+impl<'a, T: std::cmp::PartialOrd> Iterator for Difference<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        match &mut self.inner {
+            SetWalk::Stitch { a, b } => {
+                let mut x = a.next()?;
+                loop {
+                    match b.peek().map(|y| x.partial_cmp(y)) {
+                        Some(Some(Ordering::Greater)) => {
+                            b.next();
+                        }
+                        Some(Some(Ordering::Equal)) => { // x is in both, move past it on each side
+                            b.next();
+                            x = a.next()?;
+                        }
+                        _ => return Some(x), // nothing left in b at or below x
+                    }
+                }
+            }
+            SetWalk::Search { small, large } => small.find(|&x| !large.contains(x)),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match &self.inner {
+            SetWalk::Stitch { a, b } => (a.len().saturating_sub(b.len()), Some(a.len())),
+            SetWalk::Search { small, large } => (small.len().saturating_sub(large.len), Some(small.len())),
+        }
+    }
+}
+
+impl<T: std::cmp::PartialOrd> FusedIterator for Difference<'_, T> {}
+
+/// Iterator over the elements that only one of two trees holds, in ascending order, created by
+/// `Tree::symmetric_difference`
+pub struct SymmetricDifference<'a, T: std::cmp::PartialOrd> {
+    a: Peekable<Iter<'a, T>>,
+    b: Peekable<Iter<'a, T>>,
+}
+
+impl<'a, T: std::cmp::PartialOrd> Iterator for SymmetricDifference<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        loop {
+            let order = match (self.a.peek(), self.b.peek()) {
+                (Some(x), Some(y)) => x.partial_cmp(y),
+                (Some(_), None) => Some(Ordering::Less),
+                (None, _) => Some(Ordering::Greater),
+            };
+            match order {
+                Some(Ordering::Greater) => return self.b.next(),
+                Some(Ordering::Equal) => { // on both sides, skip it
+                    self.a.next();
+                    self.b.next();
+                }
+                _ => return self.a.next(),
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (a, b) = (self.a.len(), self.b.len());
+        (0, Some(a + b))
+    }
+}
+
+impl<T: std::cmp::PartialOrd> FusedIterator for SymmetricDifference<'_, T> {}
+
+/// An element yielded by `Tree::merge`, tagged with the tree it came from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MergeItem<'a, T> {
+    /// Only in the tree merge was called on
+    Left(&'a T),
+    /// Only in the tree passed to merge
+    Right(&'a T),
+    /// In both trees, left's copy first
+    Both(&'a T, &'a T),
+}
+
+/// Iterator over the elements of two trees in ascending order, each tagged with its side,
+/// created by `Tree::merge`
+pub struct Merge<'a, T: std::cmp::PartialOrd> {
+    a: Peekable<Iter<'a, T>>,
+    b: Peekable<Iter<'a, T>>,
+}
+
+impl<'a, T: std::cmp::PartialOrd> Iterator for Merge<'a, T> {
+    type Item = MergeItem<'a, T>;
+
+    fn next(&mut self) -> Option<MergeItem<'a, T>> {
+        let order = match (self.a.peek(), self.b.peek()) {
+            (Some(x), Some(y)) => x.partial_cmp(y),
+            (Some(_), None) => Some(Ordering::Less),
+            (None, _) => Some(Ordering::Greater),
+        };
+        match order {
+            Some(Ordering::Greater) => self.b.next().map(MergeItem::Right),
+            Some(Ordering::Equal) => match (self.a.next(), self.b.next()) {
+                (Some(x), Some(y)) => Some(MergeItem::Both(x, y)),
+                _ => unreachable!(), // both sides were just peeked
+            },
+            _ => self.a.next().map(MergeItem::Left),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (a, b) = (self.a.len(), self.b.len());
+        (a.max(b), Some(a + b))
+    }
+}
+
+impl<T: std::cmp::PartialOrd> FusedIterator for Merge<'_, T> {}
+
+// Iterator handing out mutable references to the elements in ascending order, created by
+// Tree::iter_mut for the map. The nodes are reached through a raw pointer because the borrow
+// checker can't see that each slot is yielded only once
+pub(crate) struct IterMut<'a, T> {
+    edges: &'a [Vec<Option<usize>>],
+    nodes: *mut Node<T>,
+    front: Option<usize>, // next element from the front
+    back: Option<usize>, // next element from the back
+    remaining: usize, // elements between front and back, stops the two ends crossing
+    marker: PhantomData<&'a mut T>,
+}
+
+impl<'a, T> IterMut<'a, T> {
+    fn element(&mut self, idx: usize) -> &'a mut T {
+        // SAFETY: nodes points at the graph of a tree that is mutably borrowed for 'a, so it can't
+        // be resized or reached any other way meanwhile. idx is a linked slot below the graph's
+        // length, and front and back stop after remaining elements, so no slot is handed out
+        // twice and the references never overlap. edges is a separate vector
+        let node = unsafe { &mut *self.nodes.add(idx) };
+        match node.data.as_deref_mut() {
+            Some(d) => d,
+            None => unreachable!(), // every linked node holds data
+        }
+    }
+}
+
+impl<'a, T> Iterator for IterMut<'a, T> {
+    type Item = &'a mut T;
+
+    fn next(&mut self) -> Option<&'a mut T> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let idx = self.front?;
+        self.front = step_in_order(self.edges, idx, true);
+        self.remaining -= 1;
+        Some(self.element(idx))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<T> DoubleEndedIterator for IterMut<'_, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let idx = self.back?;
+        self.back = step_in_order(self.edges, idx, false);
+        self.remaining -= 1;
+        Some(self.element(idx))
+    }
+}
+
+impl<T> ExactSizeIterator for IterMut<'_, T> {}
+
+impl<T> FusedIterator for IterMut<'_, T> {}
+
+// Like a mutable reference, IterMut can move to or be shared with another thread when T can
+unsafe impl<T: Send> Send for IterMut<'_, T> {}
+unsafe impl<T: Sync> Sync for IterMut<'_, T> {}
+
+// Merges two ascending sequences into one strictly ascending vector. When both hold an equal
+// element, the one from a is kept and the one from b is dropped, repeats within b are dropped too.
+// An element below the last one merged but not equal to it is out of order. Those are caught by a
+// debug assertion, in release builds they are handed back in the second vector
+fn merge_sorted<T: std::cmp::PartialOrd>(a: impl Iterator<Item = T>, b: impl Iterator<Item = T>, capacity: usize) -> (Vec<T>, Vec<T>) {
+    let mut strays: Vec<T> = Vec::new();
+    let mut merged: Vec<T> = Vec::with_capacity(capacity);
+    let mut a = a.peekable();
+    let mut b = b.peekable();
+    loop {
+        let from_a = match (a.peek(), b.peek()) {
+            (Some(x), Some(y)) => {
+                // On a tie a goes first, so b's copy is the one dropped as a repeat below
+                x.partial_cmp(y) != Some(Ordering::Greater)
+            }
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => break,
+        };
+        let next = if from_a { a.next() } else { b.next() };
+        let next = match next {
+            Some(n) => n,
+            None => unreachable!(), // the side we took from was just peeked
+        };
+        match merged.last() {
+            Some(last) if last.partial_cmp(&next) != Some(Ordering::Less) => {
+                debug_assert!(last == &next, "merged sequences must be ascending");
+                if last != &next { // not a repeat, the sequence went backwards
+                    strays.push(next);
+                }
+            }
+            _ => merged.push(next),
+        }
+    }
+    (merged, strays)
+}
+
+// Index of the element after index in sorted order, or before it when forward is false. Only
+// the edges are needed, which lets IterMut step while the nodes are lent out
+fn step_in_order(edges: &[Vec<Option<usize>>], index: usize, forward: bool) -> Option<usize> {
+    let (ahead, behind) = if forward { (2, 1) } else { (1, 2) };
+    match edges[index][ahead] {
+        Some(mut idx) => { // the nearest element on that side of the branch
+            while let Some(c) = edges[idx][behind] {
+                idx = c;
+            }
+            Some(idx)
+        }
+        None => {
+            // Climb until we come up out of a branch on the behind side, that parent is next
+            let mut child = index;
+            let mut parent = edges[index][0];
+            while let Some(p) = parent {
+                if edges[p][behind] == Some(child) {
+                    break;
+                }
+                child = p;
+                parent = edges[p][0];
+            }
+            parent
+        }
+    }
+}
+
+// Checks that value is not below the lower bound of a range
+fn within_lower<T: std::cmp::PartialOrd + ?Sized>(value: &T, bound: Bound<&T>) -> bool {
+    match bound {
+        Bound::Included(b) => value >= b,
+        Bound::Excluded(b) => value > b,
+        Bound::Unbounded => true,
+    }
+}
+
+// Checks that value is not above the upper bound of a range
+fn within_upper<T: std::cmp::PartialOrd + ?Sized>(value: &T, bound: Bound<&T>) -> bool {
+    match bound {
+        Bound::Included(b) => value <= b,
+        Bound::Excluded(b) => value < b,
+        Bound::Unbounded => true,
+    }
+}
+
+// Compile time check that Tree stays Send and Sync whenever T is. A raw pointer or Rc added to
+// the struct would make this fail to build instead of silently breaking downstream threads
+fn _assert_send_sync<T: Send + Sync>() {
+    fn _assert_send<S: Send>() {}
+    fn _assert_sync<S: Sync>() {}
+    _assert_send::<Tree<T>>();
+    _assert_sync::<Tree<T>>();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::rc::Rc;
+
+    // Small xorshift generator so the randomized tests are repeatable without extra dependencies
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    // An element that keeps count of how many of its kind are alive, to catch values that are
+    // dropped twice or never. Ordered by value alone
+    struct Counted {
+        value: u64,
+        live: Rc<Cell<usize>>,
+    }
+
+    impl Counted {
+        fn new(value: u64, live: &Rc<Cell<usize>>) -> Counted {
+            live.set(live.get() + 1);
+            Counted { value, live: Rc::clone(live) }
+        }
+    }
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.live.set(self.live.get() - 1);
+        }
+    }
+
+    impl PartialEq for Counted {
+        fn eq(&self, other: &Counted) -> bool {
+            self.value == other.value
+        }
+    }
+
+    impl PartialOrd for Counted {
+        fn partial_cmp(&self, other: &Counted) -> Option<Ordering> {
+            self.value.partial_cmp(&other.value)
+        }
+    }
+
+    // Checks every invariant the tree relies on: red-black coloring, ordering, parent pointers,
+    // the cached subtree sizes, len and that every slot is either live or on the free list
+    fn assert_valid<T: std::cmp::PartialOrd>(tree: &Tree<T>) {
+        assert!(tree.is_valid_red_black(), "red-black properties violated");
+        assert!(tree.is_bst(), "elements out of order");
+        assert_eq!(tree.validate_parent_pointers(), Ok(()));
+        assert_eq!(count_subtree(tree, tree.root), tree.len);
+        assert_eq!(tree.len + tree.empty.len(), tree.graph.len());
+    }
+
+    // Counts the nodes under index by walking them, checking each cached size on the way
+    fn count_subtree<T: std::cmp::PartialOrd>(tree: &Tree<T>, index: Option<usize>) -> usize {
+        match index {
+            Some(idx) => {
+                let count = 1 + count_subtree(tree, tree.edge_list[idx][1]) + count_subtree(tree, tree.edge_list[idx][2]);
+                assert_eq!(tree.subtree_size[idx], count, "stale subtree size at {}", idx);
+                count
+            }
+            None => 0,
+        }
+    }
+
+    #[test]
+    fn from_vec_and_array() {
+        let from_vec = Tree::from(vec![30, 10, 20]);
+        let from_array = Tree::from([20, 30, 10]);
+        assert_eq!(from_vec.to_vec(), vec![10, 20, 30]);
+        assert_eq!(from_array.to_vec(), vec![10, 20, 30]);
+        assert!(from_vec.is_valid_red_black() && from_array.is_valid_red_black());
+
+        // Repeats are dropped, leaving one copy of each value
+        let repeats = Tree::from(vec![5, 1, 5, 3, 1, 5]);
+        assert_eq!(repeats.len(), 3);
+        assert_eq!(repeats.to_vec(), vec![1, 3, 5]);
+
+        let empty: Tree<i32> = Tree::from(Vec::new());
+        assert!(empty.is_empty());
+        let empty: Tree<i32> = Tree::from([]);
+        assert_eq!(empty.root, None);
+    }
+
+    #[test]
+    fn depth_of_known_shape() {
+        // Inserted level by level, so no rotation moves anything and the tree is perfect
+        let mut tree = Tree::new();
+        for value in [4, 2, 6, 1, 3, 5, 7] {
+            tree.insert(value);
+        }
+        assert_eq!(tree.depth_of(&4), Some(0));
+        assert_eq!(tree.depth_of(&2), Some(1));
+        assert_eq!(tree.depth_of(&6), Some(1));
+        for leaf in [1, 3, 5, 7] {
+            assert_eq!(tree.depth_of(&leaf), Some(2));
+        }
+        assert_eq!(tree.depth_of(&0), None);
+        assert_eq!(tree.depth_of(&8), None);
+        assert_eq!(Tree::<i32>::new().depth_of(&1), None);
+    }
+
+    #[test]
+    fn stats_of_known_shape() {
+        // The same perfect tree as depth_of_known_shape. Inserting 1 recolors 2 and 6 black,
+        // which leaves the four leaves red
+        let mut tree = Tree::new();
+        for value in [4, 2, 6, 1, 3, 5, 7] {
+            tree.insert(value);
+        }
+        assert_eq!(tree.stats(), TreeStats { len: 7, height: 3, black_height: Some(2), red_nodes: 4, free_slots: 0 });
+        tree.remove(&3);
+        assert_eq!(tree.stats(), TreeStats { len: 6, height: 3, black_height: Some(2), red_nodes: 3, free_slots: 1 });
+        // A red node with a red child has no black height
+        let red_leaf = tree.find_index(&1).unwrap();
+        let parent = tree.edge_list[red_leaf][0].unwrap();
+        tree.color[parent] = true;
+        assert_eq!(tree.stats().black_height, None);
+        assert_eq!(Tree::<i32>::new().stats(), TreeStats { len: 0, height: 0, black_height: Some(0), red_nodes: 0, free_slots: 0 });
+    }
+
+    #[test]
+    fn for_each_visits_in_each_order() {
+        let mut rng = Rng(788);
+        let values: Vec<u64> = (0..200).map(|_| rng.below(1000)).collect();
+        let tree = inserted(&values);
+        let (mut in_order, mut pre_order, mut post_order) = (Vec::new(), Vec::new(), Vec::new());
+        tree.for_each_in_order(|x| in_order.push(*x));
+        tree.for_each_pre_order(|x| pre_order.push(*x));
+        tree.for_each_post_order(|x| post_order.push(*x));
+        assert!(in_order.iter().eq(tree.traverse(TraversalOrder::In)));
+        assert!(pre_order.iter().eq(tree.traverse(TraversalOrder::Pre)));
+        assert!(post_order.iter().eq(tree.traverse(TraversalOrder::Post)));
+
+        // try_for_each_in_order stops right at the first Break
+        let sorted = tree.to_vec();
+        let stop = sorted[57];
+        let mut seen: Vec<u64> = Vec::new();
+        let flow = tree.try_for_each_in_order(|x| {
+            seen.push(*x);
+            if *x == stop { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+        });
+        assert_eq!(flow, ControlFlow::Break(()));
+        assert!(seen.iter().eq(&sorted[..58]));
+        seen.clear();
+        let flow = tree.try_for_each_in_order(|x| {
+            seen.push(*x);
+            ControlFlow::Continue(())
+        });
+        assert_eq!(flow, ControlFlow::Continue(()));
+        assert!(seen.iter().eq(&sorted));
+        assert_eq!(Tree::<u64>::new().try_for_each_in_order(|_| ControlFlow::Break(())), ControlFlow::Continue(()));
+    }
+
+    #[test]
+    fn rebalancing_keeps_tree_valid() {
+        // Ascending and descending runs rotate the same way over and over, on both sides
+        let mut tree = Tree::new();
+        for value in 0..200 {
+            tree.insert(value);
+            assert_valid(&tree);
+        }
+        for value in (200..400).rev() {
+            tree.insert(value);
+            assert_valid(&tree);
+        }
+        for value in (0..400).step_by(3) {
+            tree.remove(&value);
+            assert_valid(&tree);
+        }
+
+        for seed in 1..100 {
+            let mut rng = Rng(seed * 7919);
+            let mut tree = Tree::new();
+            let mut reference = BTreeSet::new();
+            for _ in 0..300 {
+                let value = rng.below(100);
+                if rng.below(3) == 0 {
+                    assert_eq!(tree.remove(&value).is_some(), reference.remove(&value));
+                } else {
+                    assert_eq!(tree.insert(value), reference.insert(value));
+                }
+                assert_valid(&tree);
+            }
+            assert!(tree.iter().eq(reference.iter()));
+        }
+    }
+
+    #[test]
+    fn extend_from_sorted_small_and_large_batches() {
+        let mut tree: Tree<u32> = (0..1000).map(|x| x * 2).collect();
+        for value in (0..100).step_by(10) {
+            tree.remove(&value);
+        }
+        let slots = tree.graph.len();
+
+        // A handful of values is inserted in place, filling freed slots instead of rebuilding
+        tree.extend_from_sorted([1, 3, 5, 7, 9]);
+        assert_valid(&tree);
+        assert_eq!(tree.graph.len(), slots);
+        assert_eq!(tree.empty.len(), 5);
+        assert!(tree.contains(&5));
+
+        // A batch comparable to the tree is merged and rebuilt, which also drops the free slots
+        let mut reference: BTreeSet<u32> = tree.iter().cloned().collect();
+        let batch: Vec<u32> = (0..1500).map(|x| x * 3).collect();
+        reference.extend(batch.iter().cloned());
+        tree.extend_from_sorted(batch);
+        assert_valid(&tree);
+        assert!(tree.empty.is_empty());
+        assert!(tree.iter().eq(reference.iter()));
+
+        tree.extend_from_sorted(Vec::new());
+        assert_eq!(tree.len(), reference.len());
+    }
+
+    #[test]
+    fn extend_from_sorted_keeps_capacity() {
+        let mut tree: Tree<u32> = Tree::with_capacity(5000);
+        tree.extend((0..100).map(|x| x * 2));
+        tree.extend_from_sorted(0..1000); // large enough to rebuild
+        assert_valid(&tree);
+        assert!(tree.iter().copied().eq(0..1000));
+        assert!(tree.graph.capacity() >= 5000);
+        assert!(tree.edge_list.capacity() >= 5000);
+        assert!(tree.color.capacity() >= 5000);
+        assert!(tree.subtree_size.capacity() >= 5000);
+
+        let mut other: Tree<u32> = (5000..6000).collect();
+        tree.append(&mut other);
+        assert_valid(&tree);
+        assert!(tree.graph.capacity() >= 5000);
+    }
+
+    // Unsorted input trips a debug assertion, release builds insert the out of order elements
+    #[cfg(not(debug_assertions))]
+    #[test]
+    fn extend_from_sorted_unsorted_loses_nothing() {
+        let mut rng = Rng(774);
+        for batch_len in [3, 50, 2000] { // inserted in place, and rebuilt
+            let mut tree: Tree<u64> = (0..1000).map(|_| rng.below(5000)).collect();
+            let mut reference: BTreeSet<u64> = tree.iter().copied().collect();
+            let batch: Vec<u64> = (0..batch_len).map(|_| rng.below(5000)).collect();
+            reference.extend(batch.iter().copied());
+            tree.extend_from_sorted(batch);
+            assert_valid(&tree);
+            assert!(tree.iter().eq(reference.iter()));
+        }
+    }
+
+    // A random bound below, inside or above the values 0..100 the tests fill trees with
+    fn random_bound(rng: &mut Rng) -> Bound<u64> {
+        let value = rng.below(110);
+        match rng.below(3) {
+            0 => Bound::Included(value),
+            1 => Bound::Excluded(value),
+            _ => Bound::Unbounded,
+        }
+    }
+
+    #[test]
+    fn remove_range_leaves_the_complement() {
+        for seed in 1..300 {
+            let mut rng = Rng(seed * 17);
+            let mut tree = Tree::new();
+            for _ in 0..rng.below(150) {
+                tree.insert(rng.below(100));
+            }
+            let range = (random_bound(&mut rng), random_bound(&mut rng)); // may well be inverted
+            let expected: Vec<u64> = tree.iter().filter(|v| !range.contains(*v)).cloned().collect();
+            tree.remove_range(range);
+            assert_valid(&tree);
+            assert_eq!(tree.to_vec(), expected);
+
+            // What is left is still a working tree
+            while !tree.is_empty() {
+                let value = tree[rng.below(tree.len() as u64) as usize];
+                tree.remove(&value);
+                assert!(tree.is_valid_red_black());
+                assert_eq!(tree.validate_parent_pointers(), Ok(()));
+            }
+        }
+    }
+
+    #[test]
+    fn traverse_each_order() {
+        for seed in 1..100 {
+            let mut rng = Rng(seed * 31);
+            let mut tree = Tree::new();
+            for _ in 0..rng.below(120) {
+                let value = rng.below(200);
+                if rng.below(4) == 0 {
+                    tree.remove(&value);
+                } else {
+                    tree.insert(value);
+                }
+            }
+            assert!(tree.traverse(TraversalOrder::In).eq(tree.in_order()));
+            assert!(tree.traverse(TraversalOrder::Pre).eq(tree.pre_order()));
+            assert!(tree.traverse(TraversalOrder::Post).eq(tree.post_order()));
+
+            // Level order is sorted order regrouped by depth, left to right within a level
+            let mut by_depth: Vec<(usize, &u64)> = tree.iter_annotated().map(|n| (n.depth, n.value)).collect();
+            by_depth.sort_by_key(|&(depth, _)| depth);
+            assert!(tree.traverse(TraversalOrder::Level).eq(by_depth.into_iter().map(|(_, v)| v)));
+
+            for order in [TraversalOrder::In, TraversalOrder::Pre, TraversalOrder::Post, TraversalOrder::Level] {
+                let mut it = tree.traverse(order);
+                assert_eq!(it.len(), tree.len());
+                it.next();
+                assert_eq!(it.len(), tree.len().saturating_sub(1));
+            }
+        }
+        let empty: Tree<u64> = Tree::new();
+        assert_eq!(empty.traverse(TraversalOrder::Level).next(), None);
+    }
+
+    #[test]
+    fn fold_range_sums_an_interval() {
+        let tree: Tree<u64> = (0..100).collect();
+        assert_eq!(tree.fold_range(10..20, 0, |sum, x| sum + x), (10..20).sum::<u64>());
+        assert_eq!(tree.fold_range(95.., 0, |sum, x| sum + x), 95 + 96 + 97 + 98 + 99);
+        assert_eq!(tree.fold_range(..=2, Vec::new(), |mut v, x| { v.push(*x); v }), vec![0, 1, 2]);
+        assert_eq!(tree.fold_range(200..300, 7, |sum, x| sum + x), 7); // nothing in range
+        for seed in 1..50 {
+            let mut rng = Rng(seed * 3);
+            let tree: Tree<u64> = (0..rng.below(200)).map(|_| rng.below(100)).collect();
+            let (lo, hi) = (rng.below(100), rng.below(100));
+            let expected: u64 = tree.iter().filter(|x| (lo..hi).contains(*x)).sum();
+            assert_eq!(tree.fold_range(lo..hi, 0, |sum, x| sum + x), expected);
+        }
+    }
+
+    #[test]
+    fn removed_values_are_dropped() {
+        let live = Rc::new(Cell::new(0));
+        let mut rng = Rng(99);
+        let mut tree = Tree::new();
+        for _ in 0..2000 {
+            let value = rng.below(200);
+            match rng.below(3) {
+                0 => {
+                    // The removed value comes back to the caller and is dropped here
+                    tree.remove(&Counted::new(value, &live));
+                }
+                _ => {
+                    // A duplicate is dropped by insert instead of being stored
+                    tree.insert(Counted::new(value, &live));
+                }
+            }
+            assert_eq!(live.get(), tree.len());
+        }
+        assert!(tree.remove_min());
+        assert!(tree.remove_max());
+        assert_eq!(live.get(), tree.len());
+
+        tree.clear();
+        assert_eq!(live.get(), 0);
+        for value in 0..100 {
+            tree.insert(Counted::new(value, &live));
+        }
+        assert_eq!(live.get(), 100);
+        drop(tree);
+        assert_eq!(live.get(), 0);
+    }
+
+    #[test]
+    fn get_range_matches_a_filtered_baseline() {
+        for seed in 1..200 {
+            let mut rng = Rng(seed * 23);
+            let tree: Tree<u64> = (0..rng.below(150)).map(|_| rng.below(100)).collect();
+            let range = (random_bound(&mut rng), random_bound(&mut rng));
+            let got = tree.get_range(range);
+            assert!(got.windows(2).all(|w| w[0] < w[1]));
+            let expected: Vec<&u64> = tree.iter().filter(|x| range.contains(*x)).collect();
+            assert_eq!(got, expected);
+        }
+    }
+
+    #[test]
+    fn range_matches_a_filtered_baseline() {
+        for seed in 1..300 {
+            let mut rng = Rng(seed * 29);
+            let tree: Tree<u64> = (0..rng.below(150)).map(|_| rng.below(100)).collect();
+            let range = (random_bound(&mut rng), random_bound(&mut rng)); // inverted about half the time
+            let expected: Vec<&u64> = tree.iter().filter(|x| range.contains(*x)).collect();
+            assert!(tree.range(range).eq(expected.iter().copied()));
+            assert!(tree.range(range).rev().eq(expected.iter().rev().copied()));
+        }
+        // Inverted and empty ranges yield nothing from either end rather than panicking
+        let tree: Tree<u64> = (0..100).collect();
+        for range in [(Bound::Included(60), Bound::Included(40)), (Bound::Excluded(50), Bound::Excluded(50)), (Bound::Included(50), Bound::Excluded(50)), (Bound::Excluded(50), Bound::Included(50))] {
+            assert_eq!(tree.range(range).next(), None);
+            assert_eq!(tree.range(range).next_back(), None);
+        }
+        assert_eq!(tree.range((Bound::Excluded(49), Bound::Excluded(51))).collect::<Vec<_>>(), vec![&50]);
+        assert!(tree.range(..).eq(tree.iter()));
+    }
+
+    #[test]
+    fn join_disjoint_any_heights() {
+        let mut empty_left: Tree<u64> = Tree::new();
+        empty_left.join_disjoint((0..10).collect());
+        assert_valid(&empty_left);
+        assert!(empty_left.iter().copied().eq(0..10));
+
+        let mut empty_right: Tree<u64> = (0..10).collect();
+        empty_right.join_disjoint(Tree::new());
+        assert_valid(&empty_right);
+        assert!(empty_right.iter().copied().eq(0..10));
+
+        // Uneven sizes on either side give every difference in black height
+        for (left, right) in [(1, 1), (1, 1000), (1000, 1), (3, 500), (500, 3), (64, 65), (200, 2000)] {
+            let mut tree: Tree<u64> = (0..left).collect();
+            let other: Tree<u64> = (left..left + right).collect();
+            tree.join_disjoint(other);
+            assert_valid(&tree);
+            assert!(tree.iter().copied().eq(0..left + right));
+        }
+
+        // Trees left lopsided by removals rather than freshly built
+        let mut rng = Rng(5);
+        for _ in 0..50 {
+            let mut tree: Tree<u64> = (0..rng.below(300)).collect();
+            let mut other: Tree<u64> = (1000..1000 + rng.below(300)).collect();
+            for _ in 0..rng.below(100) {
+                let value = rng.below(300);
+                tree.remove(&value);
+                other.remove(&(value + 1000));
+            }
+            let expected: Vec<u64> = tree.iter().chain(other.iter()).copied().collect();
+            tree.join_disjoint(other);
+            assert_valid(&tree);
+            assert!(tree.iter().eq(expected.iter()));
+        }
+    }
+
+    #[test]
+    fn join_disjoint_moves_the_smaller_tree() {
+        // The larger tree's elements stay in their boxes whichever side it is on, apart from the
+        // one element taken out to join the two
+        for (left, right) in [(5, 1000), (1000, 5)] {
+            let mut tree: Tree<u64> = (0..left).collect();
+            let other: Tree<u64> = (left..left + right).collect();
+            let larger = if left > right { &tree } else { &other };
+            let before: Vec<*const u64> = larger.iter().map(|x| x as *const u64).collect();
+            tree.join_disjoint(other);
+            assert_valid(&tree);
+            let range = if left > right { 0..left - 1 } else { left + 1..left + right };
+            let after: Vec<*const u64> = tree.range(range).map(|x| x as *const u64).collect();
+            let kept = if left > right { &before[..before.len() - 1] } else { &before[1..] };
+            assert_eq!(after, kept);
+        }
+    }
+
+    // A tree built by inserting values one at a time in the given order. `From` sorts and builds
+    // a balanced tree instead, which would skip the rotations under test
+    fn inserted(values: &[u64]) -> Tree<u64> {
+        let mut tree = Tree::new();
+        for value in values {
+            tree.insert(*value);
+        }
+        tree
+    }
+
+    // (value, depth, color, position) of every node in ascending order
+    fn shape(tree: &Tree<u64>) -> Vec<(u64, usize, Color, NodePosition)> {
+        tree.iter_annotated().map(|n| (*n.value, n.depth, n.color, n.position)).collect()
+    }
+
+    #[test]
+    fn left_left_insert_rotates_right() {
+        use Color::{Black, Red};
+        use NodePosition::{LeftChild, RightChild, Root};
+        // 10 hangs off the left of 20, which hangs off the left of 30: a single right rotation
+        // at 30 makes 20 the black root with two red children
+        let rotated = inserted(&[30, 20, 10]);
+        assert_eq!(shape(&rotated), vec![(10, 1, Red, LeftChild), (20, 0, Black, Root), (30, 1, Red, RightChild)]);
+        // Inserting the middle first gives that shape with no rotation at all
+        let expected = inserted(&[20, 10, 30]);
+        assert!(rotated.structurally_eq(&expected));
+        // The left-right case rotates twice to end up in the same place
+        assert!(inserted(&[30, 10, 20]).structurally_eq(&expected));
+
+        // A fourth insert recolors instead of rotating, and the side it lands on shows up in the shape
+        let mut right = rotated.clone();
+        right.insert(40);
+        assert_eq!(shape(&right), vec![(10, 1, Black, LeftChild), (20, 0, Black, Root), (30, 1, Black, RightChild), (40, 2, Red, RightChild)]);
+        let mut left = rotated;
+        left.insert(5);
+        assert_eq!(shape(&left), vec![(5, 2, Red, LeftChild), (10, 1, Black, LeftChild), (20, 0, Black, Root), (30, 1, Black, RightChild)]);
+        let same_contents = inserted(&[5, 10, 20, 30]); // ascending inserts lean the other way
+        assert_eq!(same_contents, left);
+        assert!(!same_contents.structurally_eq(&left));
+    }
+
+    #[test]
+    fn bound_positions_match_partition_point() {
+        for seed in 1..100 {
+            let mut rng = Rng(seed * 31);
+            let tree: Tree<u64> = (0..rng.below(200)).map(|_| rng.below(150)).collect();
+            let sorted: Vec<u64> = tree.iter().copied().collect();
+            for value in 0..160 { // values both in the tree and missing from it, past either end
+                let lower = sorted.partition_point(|x| *x < value);
+                let upper = sorted.partition_point(|x| *x <= value);
+                assert_eq!(tree.lower_bound_position(&value), lower);
+                assert_eq!(tree.upper_bound_position(&value), upper);
+                // The positions index straight into select
+                assert_eq!(tree.select(lower), sorted.get(lower));
+                assert_eq!(upper - lower, usize::from(tree.contains(&value)));
+            }
+        }
+    }
+
+    #[test]
+    fn len_matches_btreeset() {
+        let mut rng = Rng(751);
+        let mut tree = Tree::new();
+        let mut reference = BTreeSet::new();
+        assert_eq!(tree.len(), 0);
+        assert!(tree.is_empty());
+        for _ in 0..5000 {
+            let value = rng.below(300);
+            match rng.below(5) {
+                0 | 1 => {
+                    tree.insert(value);
+                    reference.insert(value);
+                }
+                2 => {
+                    tree.replace(value); // overwriting an equal element must not count it twice
+                    reference.replace(value);
+                }
+                3 => {
+                    tree.remove(&value);
+                    reference.remove(&value);
+                }
+                _ => {
+                    assert_eq!(tree.pop_min(), reference.pop_first());
+                }
+            }
+            assert_eq!(tree.len(), reference.len());
+            assert_eq!(tree.is_empty(), reference.is_empty());
+        }
+        tree.clear();
+        assert_eq!(tree.len(), 0);
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn clear_keeps_capacity() {
+        let live = Rc::new(Cell::new(0));
+        let mut tree = Tree::with_capacity(100);
+        for value in 0..100 {
+            tree.insert(Counted::new(value, &live));
+        }
+        let capacity = (tree.graph.capacity(), tree.edge_list.capacity(), tree.color.capacity());
+        tree.clear();
+        assert_eq!(live.get(), 0); // every element dropped exactly once
+        assert!(tree.is_empty() && tree.root.is_none() && tree.empty.is_empty());
+
+        // Refilling reuses the same vectors and ends up like a fresh tree filled the same way
+        let mut fresh = Tree::new();
+        for value in [50, 20, 80, 10, 30] {
+            tree.insert(Counted::new(value, &live));
+            fresh.insert(Counted::new(value, &live));
+        }
+        assert_eq!((tree.graph.capacity(), tree.edge_list.capacity(), tree.color.capacity()), capacity);
+        assert!(tree.structurally_eq(&fresh));
+        assert_eq!(tree.graph.len(), fresh.graph.len());
+    }
+
+    #[test]
+    fn pop_min_and_pop_max_drain_in_order() {
+        for seed in 1..30 {
+            let mut rng = Rng(seed * 755);
+            let values: Vec<u64> = (0..rng.below(300)).map(|_| rng.below(1000)).collect();
+            let mut reference: Vec<u64> = values.clone();
+            reference.sort_unstable();
+            reference.dedup();
+
+            let mut tree: Tree<u64> = Tree::new();
+            tree.extend(values.iter().copied());
+            let mut popped = Vec::new();
+            while let Some(value) = tree.pop_min() {
+                popped.push(value);
+                assert_valid(&tree);
+            }
+            assert_eq!(popped, reference);
+            assert!(tree.is_empty() && tree.root.is_none());
+
+            tree.extend(values.iter().copied());
+            let mut popped = Vec::new();
+            while let Some(value) = tree.pop_max() {
+                popped.push(value);
+                assert_valid(&tree);
+            }
+            reference.reverse();
+            assert_eq!(popped, reference);
+            assert!(tree.is_empty() && tree.root.is_none());
+            assert_eq!(tree.pop_min(), None);
+            assert_eq!(tree.pop_max(), None);
+        }
+    }
+
+    #[test]
+    fn floor_ceiling_lower_higher() {
+        let tree: Tree<i32> = Tree::from([10, 20, 30, 40]);
+        // Below the minimum
+        assert_eq!((tree.floor(&5), tree.ceiling(&5), tree.lower(&5), tree.higher(&5)), (None, Some(&10), None, Some(&10)));
+        // Above the maximum
+        assert_eq!((tree.floor(&45), tree.ceiling(&45), tree.lower(&45), tree.higher(&45)), (Some(&40), None, Some(&40), None));
+        // Equal to a stored element, where only the strict variants move past it
+        assert_eq!((tree.floor(&20), tree.ceiling(&20), tree.lower(&20), tree.higher(&20)), (Some(&20), Some(&20), Some(&10), Some(&30)));
+        assert_eq!((tree.lower(&10), tree.higher(&40)), (None, None));
+        // Between two stored elements
+        assert_eq!((tree.floor(&25), tree.ceiling(&25), tree.lower(&25), tree.higher(&25)), (Some(&20), Some(&30), Some(&20), Some(&30)));
+        let empty: Tree<i32> = Tree::new();
+        assert_eq!((empty.floor(&1), empty.ceiling(&1), empty.lower(&1), empty.higher(&1)), (None, None, None, None));
+
+        let mut rng = Rng(757);
+        let tree: Tree<u64> = (0..300).map(|_| rng.below(1000)).collect();
+        let sorted = tree.to_vec();
+        for x in 0..1010 {
+            assert_eq!(tree.floor(&x), sorted.iter().rev().find(|d| **d <= x));
+            assert_eq!(tree.ceiling(&x), sorted.iter().find(|d| **d >= x));
+            assert_eq!(tree.lower(&x), sorted.iter().rev().find(|d| **d < x));
+            assert_eq!(tree.higher(&x), sorted.iter().find(|d| **d > x));
+        }
+    }
+
+    #[test]
+    fn min_max_after_slots_are_reused() {
+        let mut rng = Rng(754);
+        let mut tree: Tree<u64> = Tree::new();
+        let mut reference: BTreeSet<u64> = BTreeSet::new();
+        assert_eq!((Tree::min(&tree), Tree::max(&tree)), (None, None));
+        let mut reused = 0; // inserts that went into a slot freed by a removal
+        for round in 0..2000 {
+            let value = rng.below(500);
+            // Removing the extremes is the case most likely to leave them stale
+            if round % 3 == 0 {
+                let extreme = if rng.below(2) == 0 { reference.first() } else { reference.last() };
+                if let Some(&x) = extreme {
+                    assert_eq!(tree.remove(&x), Some(x));
+                    reference.remove(&x);
+                }
+            } else if rng.below(2) == 0 {
+                assert_eq!(tree.remove(&value).is_some(), reference.remove(&value));
+            } else {
+                let free = tree.empty.len();
+                tree.insert(value);
+                reference.insert(value);
+                if tree.empty.len() < free {
+                    reused += 1;
+                }
+            }
+            assert_eq!(Tree::min(&tree), reference.first());
+            assert_eq!(Tree::max(&tree), reference.last());
+        }
+        assert!(reused > 100);
+        assert_valid(&tree);
+    }
+
+    // Ordered by id first, so a comparator projecting id agrees with the tree's order
+    #[derive(Debug, Clone, PartialEq, PartialOrd)]
+    struct Record {
+        id: u32,
+        name: String,
+    }
+
+    fn record(id: u32, name: &str) -> Record {
+        Record { id, name: name.to_string() }
+    }
+
+    #[test]
+    fn find_by_key_projection() {
+        let tree: Tree<Record> = (0..200).map(|id| record(id * 5, &format!("r{}", id))).collect();
+        for target in 0..1010 {
+            let found = tree.find_by(|r| r.id.cmp(&target));
+            assert_eq!(found, tree.iter().find(|r| r.id == target), "id {}", target);
+            assert_eq!(found.is_some(), target % 5 == 0 && target < 1000);
+        }
+        assert_eq!(tree.find_by(|r| r.id.cmp(&35)).map(|r| r.name.as_str()), Some("r7"));
+        assert_eq!(Tree::<Record>::new().find_by(|r| r.id.cmp(&0)), None);
+    }
+
+    #[test]
+    fn insert_by_key_projection() {
+        // Ordered by name instead of the derived id-first order, consistently for every call
+        let by_name = |a: &Record, b: &Record| a.name.cmp(&b.name);
+        let mut rng = Rng(574);
+        let mut tree: Tree<Record> = Tree::new();
+        let mut reference: BTreeMap<String, u32> = BTreeMap::new();
+        for id in 0..500 {
+            let name = format!("n{}", rng.below(300));
+            tree.insert_by(record(id, &name), by_name);
+            reference.entry(name).or_insert(id); // a repeated name is rejected, the first id stays
+            assert_eq!(tree.len(), reference.len());
+        }
+        assert!(tree.is_valid_red_black());
+        assert!(tree.iter().map(|r| (&r.name, &r.id)).eq(reference.iter()));
+        for (name, id) in &reference {
+            let found = tree.find_by(|r| r.name.as_str().cmp(name.as_str()));
+            assert_eq!(found.map(|r| r.id), Some(*id));
+        }
+        assert_eq!(tree.find_by(|r| r.name.as_str().cmp("absent")), None);
+    }
+
+    #[test]
+    fn successor_predecessor() {
+        let tree: Tree<i32> = Tree::from([10, 20, 30, 40]);
+        // (value, successor, predecessor)
+        let table = [
+            (5, Some(&10), None), // below the minimum
+            (10, Some(&20), None), // the minimum itself
+            (25, Some(&30), Some(&20)), // between two elements
+            (30, Some(&40), Some(&20)), // a stored element is skipped in both directions
+            (40, None, Some(&30)), // the maximum itself
+            (45, None, Some(&40)), // above the maximum
+        ];
+        for (value, successor, predecessor) in table {
+            assert_eq!((tree.successor(&value), tree.predecessor(&value)), (successor, predecessor), "at {}", value);
+        }
+        let single: Tree<i32> = Tree::from([7]);
+        assert_eq!((single.successor(&6), single.predecessor(&6)), (Some(&7), None));
+        assert_eq!((single.successor(&7), single.predecessor(&7)), (None, None));
+        assert_eq!((single.successor(&8), single.predecessor(&8)), (None, Some(&7)));
+        let empty: Tree<i32> = Tree::new();
+        assert_eq!((empty.successor(&1), empty.predecessor(&1)), (None, None));
+
+        let mut rng = Rng(756);
+        let tree: Tree<u64> = (0..300).map(|_| rng.below(1000)).collect();
+        let reference: BTreeSet<u64> = tree.iter().copied().collect();
+        for x in 0..1010 {
+            assert_eq!(tree.successor(&x), reference.range((Bound::Excluded(x), Bound::Unbounded)).next());
+            assert_eq!(tree.predecessor(&x), reference.range(..x).next_back());
+        }
+    }
+
+    #[test]
+    fn from_sorted_iter_every_size() {
+        // 0..=130 covers the empty tree and every partly filled and full last level up to the eighth
+        for n in 0..=130u64 {
+            let tree = Tree::from_sorted_iter(0..n);
+            assert_valid(&tree);
+            assert!(tree.iter().copied().eq(0..n));
+            assert!(tree.empty.is_empty());
+            // Exactly the nodes on the bottom level are red, unless that is the root
+            let height = tree.stats().height;
+            assert!(tree.iter_annotated().all(|info| (info.color == Color::Red) == (info.depth > 0 && info.depth + 1 == height)), "size {}", n);
+        }
+    }
+
+    #[test]
+    fn iter_from_both_directions() {
+        let tree: Tree<u64> = (1..50).map(|x| x * 2).collect(); // 2, 4, .., 98
+        let reference: BTreeSet<u64> = tree.iter().copied().collect();
+        for key in [40, 41, 0, 2, 98, 200] { // present, absent, below min, the min, the max, above max
+            assert!(tree.iter_from(&key).eq(reference.range(key..)), "iter_from {}", key);
+            assert!(tree.iter_back_from(&key).eq(reference.range(..=key).rev()), "iter_back_from {}", key);
+            assert_eq!(tree.iter_from(&key).len(), reference.range(key..).count());
+            // The forward iterator can still be walked from its far end
+            assert!(tree.iter_from(&key).rev().eq(reference.range(key..).rev()));
+        }
+        let empty: Tree<u64> = Tree::new();
+        assert_eq!(empty.iter_from(&1).next(), None);
+        assert_eq!(empty.iter_back_from(&1).next(), None);
+    }
+
+    #[test]
+    fn get_many_matches_get() {
+        let mut rng = Rng(807);
+        let tree: Tree<u64> = (0..400).map(|_| rng.below(1000)).collect();
+        let check = |queries: &[u64]| {
+            let expected: Vec<Option<&u64>> = queries.iter().map(|q| tree.get(q)).collect();
+            assert_eq!(tree.get_many(queries), expected);
+            assert_eq!(tree.contains_many(queries), queries.iter().map(|q| tree.contains(q)).collect::<Vec<bool>>());
+        };
+        check(&[]);
+        check(&[5000, 6000]); // all absent, above every element
+        let mut many: Vec<u64> = (0..300).map(|_| rng.below(1100)).collect(); // merged in one walk, with repeats
+        many.sort();
+        check(&many);
+        let few: Vec<u64> = (0..10).map(|x| x * 97).collect(); // looked up one at a time
+        check(&few);
+        assert_eq!(Tree::<u64>::new().get_many(&few), vec![None; few.len()]);
+    }
+
+    // Unsorted queries trip a debug assertion, release builds fall back to looking up each one
+    #[cfg(not(debug_assertions))]
+    #[test]
+    fn get_many_unsorted_falls_back() {
+        let mut rng = Rng(808);
+        let tree: Tree<u64> = (0..400).map(|_| rng.below(1000)).collect();
+        let queries: Vec<u64> = (0..300).map(|_| rng.below(1100)).collect();
+        assert_eq!(tree.get_many(&queries), queries.iter().map(|q| tree.get(q)).collect::<Vec<Option<&u64>>>());
+        assert_eq!(tree.contains_many(&queries), queries.iter().map(|q| tree.contains(q)).collect::<Vec<bool>>());
+    }
+
+    #[test]
+    fn subtree_sizes_survive_every_update() {
+        for seed in 1..60 {
+            let mut rng = Rng(seed * 758);
+            let mut tree: Tree<u64> = Tree::new();
+            for _ in 0..400 {
+                let value = rng.below(200);
+                match rng.below(8) {
+                    0..=3 => {
+                        tree.insert(value);
+                    }
+                    4 | 5 => {
+                        tree.remove(&value);
+                    }
+                    6 => {
+                        tree.remove_range(value..value + rng.below(10));
+                    }
+                    _ => {
+                        tree.retain(|x| x % 7 != value % 7);
+                    }
+                }
+                // count_subtree recounts every subtree and compares it with the cached size
+                assert_eq!(count_subtree(&tree, tree.root), tree.len());
+            }
+        }
+    }
+
+    #[test]
+    fn select_and_rank_follow_the_sorted_order() {
+        let mut rng = Rng(759);
+        let mut tree: Tree<u64> = Tree::new();
+        let mut sorted: Vec<u64> = Vec::new();
+        for _ in 0..1500 {
+            let value = rng.below(250);
+            let at = sorted.partition_point(|x| *x < value);
+            let present = sorted.get(at) == Some(&value);
+            if rng.below(3) == 0 {
+                assert_eq!(tree.remove(&value).is_some(), present);
+                if present {
+                    sorted.remove(at);
+                }
+            } else {
+                assert_eq!(tree.insert(value), !present);
+                if !present {
+                    sorted.insert(at, value);
+                }
+            }
+            for (k, x) in sorted.iter().enumerate() {
+                assert_eq!(tree.select(k), Some(x));
+                assert_eq!(tree.rank(x), k);
+            }
+            assert_eq!(tree.select(sorted.len()), None);
+            assert_eq!(tree.rank(&value), sorted.partition_point(|x| *x < value));
+            assert_eq!(tree.rank(&1000), sorted.len());
+        }
+    }
+
+    #[test]
+    fn string_tree_queried_with_str() {
+        let mut tree: Tree<String> = ["pear", "apple", "fig", "banana"].iter().map(|s| s.to_string()).collect();
+        assert!(tree.contains("fig"));
+        assert!(!tree.contains("grape"));
+        assert!(!tree.contains("")); // below every stored string
+        assert_eq!(tree.get("apple").map(String::as_str), Some("apple"));
+        assert_eq!(tree.get("apples"), None); // a longer string with a stored prefix is a different key
+        assert_eq!(tree.remove("pear"), Some("pear".to_string()));
+        assert_eq!(tree.remove("pear"), None);
+        assert_eq!(tree.to_vec(), vec!["apple", "banana", "fig"]);
+        assert_valid(&tree);
+    }
+
+    #[test]
+    fn byte_vec_tree_queried_with_slices() {
+        let mut tree: Tree<Vec<u8>> = Tree::new();
+        for key in [&b"delta"[..], b"alpha", b"charlie", b"bravo", b""] {
+            tree.insert(key.to_vec());
+        }
+        let probe: &[u8] = b"charlie";
+        assert!(tree.contains(probe));
+        assert!(tree.contains(&b""[..]));
+        assert!(!tree.contains(&b"char"[..]));
+        assert_eq!(tree.get(&b"bravo"[..]), Some(&b"bravo".to_vec()));
+
+        // A miss takes nothing from the caller and leaves the tree alone
+        let missing: Vec<u8> = b"echo".to_vec();
+        assert_eq!(tree.remove(missing.as_slice()), None);
+        assert_eq!(tree.len(), 5);
+        assert_eq!(tree.remove(probe), Some(probe.to_vec()));
+        assert!(!tree.contains(probe));
+        assert_eq!(missing, b"echo");
+        assert_valid(&tree);
+    }
+
+    #[test]
+    fn insert_reports_duplicates() {
+        let mut tree = Tree::new();
+        for value in [4, 2, 6, 1, 3, 5, 7] {
+            assert!(tree.insert(value));
+        }
+        assert!(!tree.insert(4)); // at the root
+        assert!(!tree.insert(6)); // at an interior node
+        assert!(!tree.insert(7)); // at a leaf
+        assert_eq!(tree.len(), 7);
+
+        // Values that land in slots recycled from the free list are found again like any other
+        tree.remove(&2);
+        tree.remove(&7);
+        let slots = tree.graph.len();
+        assert!(tree.insert(2));
+        assert!(tree.insert(8));
+        assert_eq!(tree.graph.len(), slots);
+        assert!(tree.empty.is_empty());
+        assert!(!tree.insert(2));
+        assert!(!tree.insert(8));
+        assert_eq!(tree.len(), 7);
+        assert_valid(&tree);
+    }
+
+    #[test]
+    fn try_insert_hands_back_duplicates() {
+        let live = Rc::new(Cell::new(0));
+        let mut tree = Tree::new();
+        for value in 0..50 {
+            assert!(tree.try_insert(Counted::new(value, &live)).is_ok());
+        }
+        let slots = tree.graph.len();
+        let rejected = match tree.try_insert(Counted::new(20, &live)) {
+            Err(value) => value,
+            Ok(()) => panic!("duplicate was inserted"),
+        };
+        // The caller gets its own value back, nothing was dropped or allocated
+        assert_eq!(rejected.value, 20);
+        assert_eq!(live.get(), 51);
+        assert_eq!(tree.graph.len(), slots);
+        drop(rejected);
+        assert_eq!(live.get(), tree.len());
+        assert_valid(&tree);
+    }
+
+    // Inserts ascending values until insert_within_capacity refuses, returning how many went in
+    fn fill_to_capacity(tree: &mut Tree<u64>) -> u64 {
+        let mut next = 0;
+        while let Ok(inserted) = tree.insert_within_capacity(next) {
+            assert!(inserted);
+            next += 1;
+        }
+        next
+    }
+
+    #[test]
+    fn insert_within_capacity_fills_to_the_limit() {
+        for mut tree in [Tree::with_capacity(100), Tree::preallocated(100)] {
+            let capacity = tree.graph.capacity();
+            let filled = fill_to_capacity(&mut tree);
+            assert!(filled >= 100);
+            assert_eq!(tree.len() as u64, filled);
+            assert_eq!(tree.graph.capacity(), capacity); // nothing was reallocated
+            assert_eq!(tree.insert_within_capacity(0), Ok(false)); // duplicates are still reported
+            assert_eq!(tree.insert_within_capacity(filled), Err(filled));
+
+            // A removal frees a slot, which takes exactly one more value
+            tree.remove(&7);
+            assert_eq!(tree.insert_within_capacity(filled), Ok(true));
+            assert_eq!(tree.insert_within_capacity(filled + 1), Err(filled + 1));
+            assert_eq!(tree.graph.capacity(), capacity);
+            assert_valid(&tree);
+        }
+        let mut unreserved: Tree<u64> = Tree::new();
+        assert_eq!(unreserved.insert_within_capacity(1), Err(1));
+    }
+
+    #[test]
+    fn get_or_insert_with_returns_the_stored_element() {
+        let mut tree: Tree<String> = Tree::new();
+        let calls = Cell::new(0);
+        let mut slots = Vec::new();
+        // Ascending keys make almost every insert rotate around the new node
+        for key in ["a", "b", "c", "d", "e", "f", "g", "h"] {
+            let stored = tree.get_or_insert_with(&key.to_string(), || {
+                calls.set(calls.get() + 1);
+                key.to_string()
+            }) as *const String;
+            assert!(std::ptr::eq(stored, tree.get(key).unwrap()));
+            assert_valid(&tree);
+            slots.push(tree.find_slot(tree.root, &key.to_string()).unwrap());
+        }
+        assert_eq!(calls.get(), 8);
+        // Later rotations moved edges around but no element changed slots
+        for (slot, key) in slots.into_iter().zip(["a", "b", "c", "d", "e", "f", "g", "h"]) {
+            assert_eq!(tree.value(slot), key);
+        }
+
+        // Present elements are returned without calling f
+        let found = tree.get_or_insert_with(&"c".to_string(), || unreachable!());
+        assert_eq!(found, "c");
+        assert_eq!(tree.len(), 8);
+    }
+
+    #[test]
+    fn retain_keeps_the_tree_valid() {
+        for seed in 1..100 {
+            let mut rng = Rng(seed * 768);
+            let values: Vec<u64> = (0..rng.below(300)).map(|_| rng.below(500)).collect();
+            let mut tree: Tree<u64> = values.iter().copied().collect();
+            let mut reference: BTreeSet<u64> = values.iter().copied().collect();
+            let (modulus, keep) = (rng.below(5) + 2, rng.below(2));
+            tree.retain(|x| x % modulus == keep);
+            reference.retain(|x| x % modulus == keep);
+            assert_valid(&tree);
+            assert!(tree.iter().eq(reference.iter()));
+            // The freed slots are reused before the vectors grow again
+            let slots = tree.graph.len();
+            let freed = tree.empty.len();
+            for value in 1000..1000 + freed as u64 {
+                tree.insert(value);
+            }
+            assert_eq!(tree.graph.len(), slots);
+            assert_valid(&tree);
+        }
+
+        let mut tree: Tree<u64> = (0..100).collect();
+        tree.retain(|_| true);
+        assert_eq!(tree.len(), 100);
+        assert!(tree.empty.is_empty());
+        tree.retain(|_| false);
+        assert!(tree.is_empty() && tree.root.is_none());
+        assert_valid(&tree);
+    }
+
+    #[test]
+    fn replaying_operations_matches_btreeset() {
+        for seed in 1..50 {
+            let mut rng = Rng(seed * 567);
+            let ops: Vec<Op<u64>> = (0..rng.below(1000))
+                .map(|_| if rng.below(3) == 0 { Op::Remove(rng.below(200)) } else { Op::Insert(rng.below(200)) })
+                .collect();
+            let mut reference: BTreeSet<u64> = BTreeSet::new();
+            let mut stepped: Tree<u64> = Tree::new();
+            for op in &ops {
+                match op {
+                    Op::Insert(x) => reference.insert(*x),
+                    Op::Remove(x) => reference.remove(x),
+                };
+                stepped.apply(op.clone());
+                assert_valid(&stepped);
+            }
+            let replayed = Tree::from_operations(ops.clone());
+            assert_valid(&replayed);
+            assert!(replayed.iter().eq(reference.iter()));
+            // A replay reproduces the exact tree, down to the shape and colors
+            assert!(replayed.structurally_eq(&stepped));
+            assert!(Tree::from_operations(ops).structurally_eq(&replayed));
+        }
+    }
+
+    #[test]
+    fn compact_squeezes_out_free_slots() {
+        let mut rng = Rng(576);
+        let values: Vec<u64> = (0..600).map(|_| rng.below(10_000)).collect();
+        let mut tree = inserted(&values);
+        for value in &values[..500] { // most of the elements, scattered through the slots
+            tree.remove(value);
+        }
+        assert!(tree.empty.len() > tree.len());
+        let before = shape(&tree);
+        tree.compact();
+        assert_valid(&tree);
+        assert!(tree.empty.is_empty());
+        assert_eq!(tree.graph.len(), tree.len());
+        assert_eq!(shape(&tree), before); // same elements, depths, colors and positions
+        // The compacted tree keeps working, new elements go on the end
+        tree.insert(20_000);
+        tree.remove(&values[550]);
+        assert_valid(&tree);
+        tree.compact();
+        assert_eq!(tree.graph.len(), tree.len());
+
+        // maybe_compact leaves a tree that is full enough alone
+        let mut tree = inserted(&values);
+        let slots = tree.graph.len();
+        for value in &values[..100] {
+            tree.remove(value);
+        }
+        assert!(!tree.maybe_compact(0.5));
+        assert_eq!(tree.graph.len(), slots);
+        for value in &values[100..500] {
+            tree.remove(value);
+        }
+        let before = shape(&tree);
+        assert!(tree.maybe_compact(0.5));
+        assert_valid(&tree);
+        assert!(tree.empty.is_empty());
+        assert_eq!(tree.graph.len(), tree.len());
+        assert!(tree.graph.capacity() < slots); // shrunk as well
+        assert_eq!(shape(&tree), before);
+        assert!(!Tree::<u64>::new().maybe_compact(1.0));
+    }
+
+    #[test]
+    fn retain_range_only_sees_the_range() {
+        for seed in 1..200 {
+            let mut rng = Rng(seed * 582);
+            let mut tree: Tree<u64> = (0..rng.below(150)).map(|_| rng.below(100)).collect();
+            let mut reference: BTreeSet<u64> = tree.iter().copied().collect();
+            let range = (random_bound(&mut rng), random_bound(&mut rng));
+            let modulus = rng.below(4) + 2;
+            let mut seen: Vec<u64> = Vec::new();
+            tree.retain_range(range, |x| {
+                seen.push(*x);
+                x % modulus == 0
+            });
+            // Every element in the range is offered exactly once, and nothing outside it
+            seen.sort();
+            assert!(seen.iter().eq(reference.iter().filter(|x| range.contains(*x))));
+            reference.retain(|x| !range.contains(x) || x % modulus == 0);
+            assert_valid(&tree);
+            assert!(tree.iter().eq(reference.iter()));
+        }
+    }
+
+    #[test]
+    fn drain_dropped_part_way() {
+        let live = Rc::new(Cell::new(0));
+        let mut tree = Tree::new();
+        for value in [5, 3, 8, 1, 4, 7, 9, 2, 6] {
+            tree.insert(Counted::new(value, &live));
+        }
+        let capacity = tree.graph.capacity();
+        {
+            let mut drain = tree.drain();
+            let taken: Vec<u64> = drain.by_ref().take(3).map(|c| c.value).collect();
+            assert_eq!(taken, vec![1, 2, 3]);
+            assert_eq!(live.get(), 6); // the taken values were dropped by map
+        } // the rest go when the iterator does
+        assert_eq!(live.get(), 0);
+        assert!(tree.is_empty() && tree.root.is_none());
+        assert_eq!(tree.graph.capacity(), capacity);
+        assert_valid(&tree);
+
+        // Usable again afterwards, and a fully consumed drain empties it the same way
+        for value in 0..20 {
+            tree.insert(Counted::new(value, &live));
+        }
+        assert!(tree.drain().map(|c| c.value).eq(0..20));
+        assert_eq!(live.get(), 0);
+        assert!(tree.is_empty());
+        assert_eq!(tree.drain().count(), 0);
+    }
+
+    #[test]
+    fn split_off_at_every_kind_of_key() {
+        let source: Tree<u64> = (1..=50).map(|x| x * 2).collect(); // even values 2..=100
+        for at in [0, 1, 2, 51, 52, 100, 101, 200] { // below, at the minimum, absent, present, past the end
+            let mut low = source.clone();
+            let high = low.split_off(&at);
+            let mut reference: BTreeSet<u64> = source.iter().copied().collect();
+            let reference_high = reference.split_off(&at);
+            assert!(low.iter().eq(reference.iter()));
+            assert!(high.iter().eq(reference_high.iter()));
+            assert_valid(&low);
+            assert_valid(&high);
+            // Each half only holds slots for its own elements
+            assert!(low.empty.is_empty() && high.empty.is_empty());
+            assert_eq!(low.graph.len(), low.len());
+            assert_eq!(high.graph.len(), high.len());
+        }
+        let mut empty: Tree<u64> = Tree::new();
+        assert!(empty.split_off(&5).is_empty());
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn append_keeps_the_existing_duplicate() {
+        for seed in 1..40 {
+            let mut rng = Rng(seed * 772);
+            let (ours, theirs) = (Rc::new(Cell::new(0)), Rc::new(Cell::new(0)));
+            let mut tree = Tree::new();
+            let mut other = Tree::new();
+            let mut reference = BTreeSet::new();
+            for _ in 0..rng.below(200) {
+                let value = rng.below(300);
+                tree.insert(Counted::new(value, &ours));
+                reference.insert(value);
+            }
+            let mut new_values = 0;
+            for _ in 0..rng.below(200) {
+                let value = rng.below(300);
+                if other.insert(Counted::new(value, &theirs)) && !reference.contains(&value) {
+                    new_values += 1;
+                }
+            }
+            reference.extend(other.iter().map(|c| c.value));
+            let before = tree.len();
+            tree.append(&mut other);
+            assert!(other.is_empty());
+            assert_valid(&tree);
+            assert!(tree.iter().map(|c| c.value).eq(reference.iter().copied()));
+            // Every element of this tree survived, and only other's new values were moved over
+            assert_eq!(ours.get(), before);
+            assert_eq!(theirs.get(), new_values);
+        }
+    }
+
+    #[test]
+    fn collect_and_extend() {
+        let mut rng = Rng(775);
+        let values: Vec<u64> = (0..500).map(|_| rng.below(200)).collect();
+        let mut tree: Tree<u64> = values.iter().copied().collect();
+        let mut reference: BTreeSet<u64> = values.iter().copied().collect();
+        assert_valid(&tree);
+        assert!(tree.iter().eq(reference.iter()));
+        assert!(tree.graph.capacity() >= values.len()); // reserved from the size hint
+
+        let more: Vec<u64> = (0..500).map(|_| rng.below(400)).collect();
+        tree.extend(more.iter().copied());
+        reference.extend(more.iter().copied());
+        assert_valid(&tree);
+        assert!(tree.iter().eq(reference.iter()));
+
+        // Like insert, the element already stored wins over an equal one that comes later
+        let (first, later) = (Rc::new(Cell::new(0)), Rc::new(Cell::new(0)));
+        let mut tree: Tree<Counted> = (0..10).map(|v| Counted::new(v, &first)).collect();
+        tree.extend((5..15).map(|v| Counted::new(v, &later)));
+        assert_eq!((first.get(), later.get()), (10, 5));
+        assert!(tree.iter().map(|c| c.value).eq(0..15));
+
+        let empty: Tree<u64> = std::iter::empty().collect();
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn from_vec_keeps_the_first_duplicate() {
+        let (first, later) = (Rc::new(Cell::new(0)), Rc::new(Cell::new(0)));
+        let values = vec![
+            Counted::new(3, &first),
+            Counted::new(1, &first),
+            Counted::new(3, &later),
+            Counted::new(2, &first),
+            Counted::new(1, &later),
+        ];
+        let tree = Tree::from(values);
+        assert!(tree.iter().map(|c| c.value).eq(1..=3));
+        assert!(tree.iter().all(|c| Rc::ptr_eq(&c.live, &first)));
+        assert_eq!(later.get(), 0); // the later copies were dropped
+
+        // Built in one pass, so the slots are packed and valid whatever the input order
+        let mut rng = Rng(776);
+        let values: Vec<u64> = (0..1000).map(|_| rng.below(600)).collect();
+        let reference: BTreeSet<u64> = values.iter().copied().collect();
+        let tree = Tree::from(values);
+        assert_valid(&tree);
+        assert_eq!(tree.graph.len(), tree.len());
+        assert!(tree.iter().eq(reference.iter()));
+        assert!(Tree::from([9, 8, 7, 8, 9]).iter().eq([7, 8, 9].iter()));
+    }
+
+    #[test]
+    fn into_iter_dropped_after_next_and_next_back() {
+        let live = Rc::new(Cell::new(0));
+        let tree: Tree<Counted> = (0..10).map(|v| Counted::new(v, &live)).collect();
+        let mut iter = tree.into_iter();
+        assert_eq!(iter.next().map(|c| c.value), Some(0));
+        assert_eq!(iter.next_back().map(|c| c.value), Some(9));
+        assert_eq!(iter.next_back().map(|c| c.value), Some(8));
+        assert_eq!(iter.len(), 7);
+        assert_eq!(live.get(), 7);
+        drop(iter); // the values still in the tree go with it, the taken ones are not dropped twice
+        assert_eq!(live.get(), 0);
+
+        // Both ends meet in the middle without yielding anything twice
+        let tree: Tree<u64> = (0..7).collect();
+        let mut iter = tree.into_iter();
+        let mut seen = Vec::new();
+        while let Some(front) = iter.next() {
+            seen.push(front);
+            seen.extend(iter.next_back());
+        }
+        seen.sort_unstable();
+        assert_eq!(seen, (0..7).collect::<Vec<u64>>());
+        assert_eq!(iter.next_back(), None);
+        assert_eq!(Tree::<u64>::new().into_iter().next(), None);
+    }
+
+    #[test]
+    fn cursor_mut_edits_in_place() {
+        let mut tree: Tree<u64> = Tree::from([10, 20, 30, 40]);
+        {
+            let mut cursor = tree.lower_bound_mut(Bound::Included(&25)); // between 20 and 30
+            assert_eq!((cursor.peek_prev(), cursor.peek_next()), (Some(&20), Some(&30)));
+            // Values outside the gap, or equal to a neighbour, are handed back
+            assert_eq!(cursor.insert_before(35), Err(35));
+            assert_eq!(cursor.insert_after(15), Err(15));
+            assert_eq!(cursor.insert_before(20), Err(20));
+            assert_eq!(cursor.insert_after(30), Err(30));
+            assert_eq!(cursor.insert_before(22), Ok(()));
+            assert_eq!((cursor.peek_prev(), cursor.peek_next()), (Some(&22), Some(&30)));
+            assert_eq!(cursor.insert_after(28), Ok(()));
+            assert_eq!((cursor.peek_prev(), cursor.peek_next()), (Some(&22), Some(&28)));
+            assert_eq!(cursor.remove_current(), Some(28));
+            assert_eq!(cursor.remove_current(), Some(30));
+            assert_eq!(cursor.peek_next(), Some(&40));
+        }
+        assert_valid(&tree);
+        assert_eq!(tree.to_vec(), vec![10, 20, 22, 40]);
+
+        // Past either end the cursor stays put and editing still respects the order
+        {
+            let mut cursor = tree.lower_bound_mut(Bound::Unbounded);
+            assert_eq!(cursor.move_prev(), None);
+            assert_eq!(cursor.insert_before(5), Ok(()));
+            assert_eq!(cursor.insert_before(4), Err(4)); // the cursor is now after 5
+            while cursor.move_next().is_some() {}
+            assert_eq!(cursor.move_next(), None);
+            assert_eq!(cursor.remove_current(), None);
+            assert_eq!(cursor.insert_after(50), Ok(()));
+            assert_eq!(cursor.move_next(), Some(&50));
+            assert_eq!(cursor.move_prev(), Some(&50));
+        }
+        assert_valid(&tree);
+        assert_eq!(tree.to_vec(), vec![5, 10, 20, 22, 40, 50]);
+
+        let mut empty: Tree<u64> = Tree::new();
+        {
+            let mut cursor = empty.lower_bound_mut(Bound::Unbounded);
+            assert_eq!((cursor.peek_prev(), cursor.peek_next()), (None, None));
+            assert_eq!(cursor.move_next(), None);
+            assert_eq!(cursor.move_prev(), None);
+            assert_eq!(cursor.remove_current(), None);
+            assert_eq!(cursor.insert_after(1), Ok(()));
+        }
+        assert_eq!(empty.to_vec(), vec![1]);
+
+        // Random edits stay valid and match BTreeSet
+        let mut rng = Rng(779);
+        let mut tree: Tree<u64> = (0..100).map(|x| x * 10).collect();
+        let mut reference: BTreeSet<u64> = tree.iter().copied().collect();
+        for _ in 0..500 {
+            let at = rng.below(1000);
+            let value = rng.below(1000);
+            let mut cursor = tree.lower_bound_mut(Bound::Included(&at));
+            match rng.below(3) {
+                0 => {
+                    if let Some(removed) = cursor.remove_current() {
+                        assert!(reference.remove(&removed));
+                    }
+                }
+                1 => {
+                    if cursor.insert_before(value).is_ok() {
+                        assert!(reference.insert(value));
+                    }
+                }
+                _ => {
+                    if cursor.insert_after(value).is_ok() {
+                        assert!(reference.insert(value));
+                    }
+                }
+            }
+            assert_valid(&tree);
+            assert!(tree.iter().eq(reference.iter()));
+            assert!(tree.iter().rev().eq(reference.iter().rev()));
+        }
+    }
+
+    #[test]
+    fn cursor_bounds_match_a_sorted_vec() {
+        let mut rng = Rng(780);
+        let tree: Tree<u64> = (0..200).map(|_| rng.below(100)).collect();
+        let sorted = tree.to_vec();
+        for x in 0..105 {
+            let first_at_least = sorted.partition_point(|d| *d < x);
+            let first_above = sorted.partition_point(|d| *d <= x);
+            for (bound, gap) in [(Bound::Included(&x), first_at_least), (Bound::Excluded(&x), first_above), (Bound::Unbounded, 0)] {
+                let cursor = tree.lower_bound(bound);
+                assert_eq!(cursor.peek_next(), sorted.get(gap));
+                assert_eq!(cursor.peek_prev(), gap.checked_sub(1).map(|i| &sorted[i]));
+            }
+            for (bound, gap) in [(Bound::Included(&x), first_above), (Bound::Excluded(&x), first_at_least), (Bound::Unbounded, sorted.len())] {
+                let cursor = tree.upper_bound(bound);
+                assert_eq!(cursor.peek_next(), sorted.get(gap));
+                assert_eq!(cursor.peek_prev(), gap.checked_sub(1).map(|i| &sorted[i]));
+            }
+        }
+
+        // Walking from a bound visits the rest of the elements in order, and stops at either end
+        let mut cursor = tree.lower_bound(Bound::Included(&50));
+        let start = sorted.partition_point(|d| *d < 50);
+        let mut walked = Vec::new();
+        while let Some(x) = cursor.move_next() {
+            walked.push(*x);
+        }
+        assert_eq!(walked, sorted[start..]);
+        assert_eq!(cursor.move_next(), None);
+        assert_eq!(cursor.move_prev(), sorted.last());
+
+        let empty: Tree<u64> = Tree::new();
+        let mut cursor = empty.upper_bound(Bound::Included(&1));
+        assert_eq!((cursor.peek_prev(), cursor.peek_next()), (None, None));
+        assert_eq!(cursor.move_prev(), None);
+
+        // Every iterator reports its exact remaining length and stays done once it ends
+        let in_range = tree.iter().filter(|x| (20..60).contains(*x)).count();
+        assert_exact(tree.iter(), tree.len());
+        assert_exact(tree.iter().rev(), tree.len());
+        assert_exact(tree.range(20..60), in_range);
+        assert_exact(tree.range(20..60).rev(), in_range);
+        assert_exact(tree.iter_pre_order(), tree.len());
+        assert_exact(tree.iter_post_order(), tree.len());
+        assert_exact(tree.iter_level_order(), tree.len());
+        assert_exact(tree.clone().into_iter(), tree.len());
+        assert_exact(tree.clone().drain(), tree.len());
+    }
+
+    fn assert_exact<I: ExactSizeIterator + FusedIterator>(mut iter: I, len: usize) {
+        for remaining in (0..=len).rev() {
+            assert_eq!(iter.size_hint(), (remaining, Some(remaining)));
+            assert_eq!(iter.len(), remaining);
+            assert_eq!(iter.next().is_some(), remaining > 0);
+        }
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn pre_and_post_order_iterators() {
+        // Level by level, so the shape is known: 4 at the root, 2 and 6 below it
+        let tree = inserted(&[4, 2, 6, 1, 3, 5, 7]);
+        assert!(tree.iter_pre_order().eq([4, 2, 1, 3, 6, 5, 7].iter()));
+        assert!(tree.iter_post_order().eq([1, 3, 2, 5, 7, 6, 4].iter()));
+
+        for seed in 1..60 {
+            let mut rng = Rng(seed * 781);
+            let mut tree = Tree::new();
+            for _ in 0..rng.below(300) {
+                let value = rng.below(250);
+                if rng.below(3) == 0 {
+                    tree.remove(&value);
+                } else {
+                    tree.insert(value);
+                }
+            }
+            assert!(tree.iter_pre_order().eq(tree.pre_order()));
+            assert!(tree.iter_post_order().eq(tree.post_order()));
+            // The root comes first before its subtrees and last after them
+            let root = tree.root.map(|r| tree.value(r));
+            assert_eq!(tree.iter_pre_order().next(), root);
+            assert_eq!(tree.iter_post_order().last(), root);
+        }
+        assert_eq!(Tree::<u64>::new().iter_pre_order().next(), None);
+        assert_eq!(Tree::<u64>::new().iter_post_order().next(), None);
+    }
+
+    #[test]
+    fn cursor_walks_to_both_ends() {
+        let tree: Tree<u64> = (1..=5).collect();
+        let mut cursor = tree.lower_bound(Bound::Unbounded);
+        assert_eq!((cursor.peek_prev(), cursor.peek_next()), (None, Some(&1)));
+        assert_eq!(cursor.move_prev(), None); // already at the start, nothing moves
+        assert_eq!(cursor.peek_next(), Some(&1));
+        let forward: Vec<u64> = std::iter::from_fn(|| cursor.move_next().copied()).collect();
+        assert_eq!(forward, vec![1, 2, 3, 4, 5]);
+        assert_eq!((cursor.peek_prev(), cursor.peek_next()), (Some(&5), None));
+        assert_eq!(cursor.move_next(), None);
+        let backward: Vec<u64> = std::iter::from_fn(|| cursor.move_prev().copied()).collect();
+        assert_eq!(backward, vec![5, 4, 3, 2, 1]);
+        assert_eq!((cursor.peek_prev(), cursor.peek_next()), (None, Some(&1)));
+
+        // Stepping back and forth around a key returns the same elements
+        let mut cursor = tree.upper_bound(Bound::Included(&3));
+        assert_eq!(cursor.move_prev(), Some(&3));
+        assert_eq!(cursor.move_next(), Some(&3));
+        assert_eq!(cursor.move_next(), Some(&4));
+        assert_eq!(tree.upper_bound(Bound::Unbounded).peek_prev(), Some(&5));
+
+        let single: Tree<u64> = Tree::from([7]);
+        let mut cursor = single.lower_bound(Bound::Excluded(&7));
+        assert_eq!((cursor.peek_prev(), cursor.peek_next()), (Some(&7), None));
+        assert_eq!(cursor.move_prev(), Some(&7));
+        assert_eq!(cursor.move_prev(), None);
+
+        let empty: Tree<u64> = Tree::new();
+        for mut cursor in [empty.lower_bound(Bound::Unbounded), empty.upper_bound(Bound::Unbounded)] {
+            assert_eq!((cursor.peek_prev(), cursor.peek_next()), (None, None));
+            assert_eq!(cursor.move_next(), None);
+            assert_eq!(cursor.move_prev(), None);
+        }
+    }
+
+    #[test]
+    fn leaves_of_known_and_random_shapes() {
+        assert!(inserted(&[4, 2, 6, 1, 3, 5, 7]).leaves().eq([1, 3, 5, 7].iter()));
+        // A single node is its own leaf
+        assert!(inserted(&[9]).leaves().eq([9].iter()));
+        // The root has one child on either side, only that child is a leaf
+        assert!(inserted(&[2, 1]).leaves().eq([1].iter()));
+        assert!(inserted(&[1, 2]).leaves().eq([2].iter()));
+        assert_eq!(Tree::<u64>::new().leaves().next(), None);
+
+        for seed in 1..60 {
+            let mut rng = Rng(seed * 786);
+            let tree: Tree<u64> = (0..rng.below(300)).map(|_| rng.below(500)).collect();
+            let childless: Vec<&u64> = tree
+                .iter_annotated()
+                .filter(|n| tree.find_slot(tree.root, n.value).is_ok_and(|i| tree.edge_list[i][1..].iter().all(Option::is_none)))
+                .map(|n| n.value)
+                .collect();
+            assert!(tree.leaves().eq(childless));
+            // A binary tree of n nodes has at most (n + 1) / 2 leaves
+            assert!(tree.leaves().count() <= tree.len().div_ceil(2));
+            // Every leaf has the same number of black nodes on its path to the root
+            let black_counts: BTreeSet<usize> = tree
+                .leaves()
+                .map(|leaf| {
+                    let mut index = tree.find_slot(tree.root, leaf).ok();
+                    let mut blacks = 0;
+                    while let Some(i) = index {
+                        blacks += usize::from(!tree.color[i]);
+                        index = tree.edge_list[i][0];
+                    }
+                    blacks
+                })
+                .collect();
+            assert!(black_counts.len() <= 1);
+        }
+    }
+
+    #[test]
+    fn clone_is_independent() {
+        let mut rng = Rng(790);
+        let mut original: Tree<String> = (0..300).map(|_| rng.below(1000).to_string()).collect();
+        for _ in 0..150 { // leaves freed slots scattered through the vectors
+            original.remove(rng.below(1000).to_string().as_str());
+        }
+        assert!(!original.empty.is_empty());
+        let before = original.to_vec();
+
+        let mut copy = original.clone();
+        assert_valid(&copy);
+        assert_eq!(copy, original);
+        assert!(copy.structurally_eq(&original));
+        // The clone only holds live elements, the free slots are squeezed out
+        assert!(copy.empty.is_empty());
+        assert_eq!(copy.graph.len(), copy.len());
+
+        for _ in 0..300 {
+            let value = rng.below(1000).to_string();
+            if rng.below(2) == 0 {
+                copy.remove(value.as_str());
+            } else {
+                copy.insert(value);
+            }
+        }
+        copy.pop_min();
+        assert_valid(&copy);
+        assert_eq!(original.to_vec(), before);
+        assert_valid(&original);
+
+        // clone_from into a tree that already has contents replaces them completely
+        let mut reused: Tree<String> = (0..5).map(|x| x.to_string()).collect();
+        reused.clone_from(&original);
+        assert_valid(&reused);
+        assert_eq!(reused.to_vec(), before);
+        reused.clear();
+        assert_eq!(original.to_vec(), before);
+    }
+
+    #[test]
+    fn clone_from_reuses_buffers() {
+        let mut rng = Rng(565);
+        let mut sources: Vec<Tree<String>> = Vec::new();
+        for _ in 0..4 { // same length, different contents and slot layouts
+            let mut source: Tree<String> = Tree::new();
+            while source.len() < 200 {
+                source.insert(rng.below(1000).to_string());
+                if rng.below(3) == 0 {
+                    source.remove(rng.below(1000).to_string().as_str());
+                }
+            }
+            sources.push(source);
+        }
+        // Everything clone_from could reallocate: the vectors, each edge vector and each box
+        fn buffers(tree: &Tree<String>) -> (Vec<usize>, Vec<*const Option<usize>>, Vec<*const String>) {
+            let capacities = vec![tree.graph.capacity(), tree.edge_list.capacity(), tree.color.capacity(), tree.subtree_size.capacity()];
+            let edges = tree.edge_list.iter().map(|e| e.as_ptr()).collect();
+            let boxes = tree.graph.iter().map(|n| n.data.as_deref().unwrap() as *const String).collect();
+            (capacities, edges, boxes)
+        }
+        let mut reused = sources[0].clone();
+        let before = buffers(&reused);
+        for source in sources.iter().cycle().skip(1).take(8) {
+            reused.clone_from(source);
+            assert_valid(&reused);
+            assert!(reused.structurally_eq(source));
+            assert!(reused.empty.is_empty());
+            assert_eq!(buffers(&reused), before);
+        }
+    }
+
+    fn hash_of<T: Hash>(value: &T) -> u64 {
+        use std::collections::hash_map::DefaultHasher;
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn equal_contents_compare_and_hash_equal() {
+        let values: Vec<u64> = (0..200).map(|x| x * 7 % 211).collect();
+        // Ascending inserts, descending inserts, a bulk build and a tree that had extra elements
+        // removed again all end up with different shapes and slot layouts
+        let ascending = inserted(&(0..211).filter(|x| values.contains(x)).collect::<Vec<u64>>());
+        let descending = inserted(&(0..211).rev().filter(|x| values.contains(x)).collect::<Vec<u64>>());
+        let built = Tree::from(values.clone());
+        let mut pruned: Tree<u64> = (0..300).collect();
+        pruned.retain(|x| values.contains(x));
+        assert!(!ascending.structurally_eq(&descending));
+        for tree in [&descending, &built, &pruned] {
+            assert_eq!(&ascending, tree);
+            assert_eq!(hash_of(&ascending), hash_of(tree));
+        }
+
+        let mut different = built.clone();
+        different.remove(&values[0]);
+        assert_ne!(built, different);
+        different.insert(1000);
+        assert_ne!(built, different); // same length, different contents
+        assert_eq!(Tree::<u64>::new(), Tree::new());
+        assert_eq!(hash_of(&Tree::<u64>::new()), hash_of(&Tree::<u64>::new()));
+        assert_ne!(Tree::<u64>::new(), Tree::from([0]));
+    }
+
+    #[test]
+    fn trees_order_lexicographically() {
+        let empty: Tree<u64> = Tree::new();
+        let short = Tree::from([1, 2]);
+        let long = Tree::from([1, 2, 3]);
+        let bigger = Tree::from([1, 5]);
+        assert!(empty < short); // the empty tree is a prefix of every other
+        assert!(short < long); // a prefix sorts first
+        assert!(long < bigger); // the first difference decides, not the length
+        assert_eq!(empty.cmp(&Tree::new()), Ordering::Equal);
+        assert_eq!(long.cmp(&Tree::from([3, 2, 1])), Ordering::Equal);
+        assert_eq!([&bigger, &empty, &long, &short].iter().max(), Some(&&bigger));
+
+        // Same answer as BTreeSet for random pairs, and hashing agrees with equality
+        let mut rng = Rng(793);
+        for _ in 0..500 {
+            let a: BTreeSet<u64> = (0..rng.below(6)).map(|_| rng.below(8)).collect();
+            let b: BTreeSet<u64> = (0..rng.below(6)).map(|_| rng.below(8)).collect();
+            let (ta, tb) = (Tree::from(a.clone()), Tree::from(b.clone()));
+            assert_eq!(ta.cmp(&tb), a.cmp(&b));
+            assert_eq!(ta.partial_cmp(&tb), Some(a.cmp(&b)));
+            if ta == tb {
+                assert_eq!(hash_of(&ta), hash_of(&tb));
+            }
+        }
+        // PartialOrd alone is enough, e.g. for floats
+        assert!(Tree::from(vec![1.0, 2.5]) < Tree::from(vec![1.5]));
+    }
+
+    #[test]
+    fn debug_output() {
+        let tree = inserted(&[2, 1, 3]);
+        assert_eq!(format!("{:?}", tree), "{1, 2, 3}");
+        assert_eq!(format!("{:?}", tree), format!("{:?}", tree.iter().collect::<BTreeSet<_>>()));
+        assert_eq!(format!("{:#?}", tree), "2 (black)\n    L: 1 (red)\n    R: 3 (red)");
+
+        let mut deeper = inserted(&[2, 1, 3, 4]);
+        deeper.remove(&1); // a freed slot doesn't show up
+        assert_eq!(format!("{:#?}", deeper), "3 (black)\n    L: 2 (black)\n    R: 4 (black)");
+        // One line per node and no newline after the last, like the std Debug impls
+        let big: Tree<u64> = (0..50).collect();
+        let text = format!("{:#?}", big);
+        assert!(!text.ends_with('\n'));
+        assert_eq!(text.lines().count(), 50);
+
+        let words: Tree<String> = ["b", "a"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(format!("{:?}", words), r#"{"a", "b"}"#);
+
+        let empty: Tree<u64> = Tree::new();
+        assert_eq!(format!("{:?}", empty), "{}");
+        assert_eq!(format!("{:#?}", empty), "(empty)");
+    }
+
+    // Writes tree to a buffer, lets corrupt change the bytes and reads it back. The trees these
+    // tests corrupt have no free slots, so slot i of a u64 tree starts at 32 + 33 * i: a flags
+    // byte, then parent, left and right as u64, then the value
+    fn corrupted(tree: &Tree<u64>, corrupt: impl FnOnce(&mut Vec<u8>)) -> io::Result<Tree<u64>> {
+        let mut bytes = Vec::new();
+        tree.write_snapshot(&mut bytes).unwrap();
+        corrupt(&mut bytes);
+        Tree::read_snapshot(bytes.as_slice())
+    }
+
+    fn set_edge(bytes: &mut [u8], slot: usize, edge: usize, to: u64) {
+        let at = 32 + 33 * slot + 1 + 8 * edge;
+        bytes[at..at + 8].copy_from_slice(&to.to_le_bytes());
+    }
+
+    fn assert_invalid(result: io::Result<Tree<u64>>) {
+        match result {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{}", e),
+            Ok(_) => panic!("corrupted snapshot was accepted"),
+        }
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let mut tree: Tree<u64> = (0..200).collect();
+        for value in (0..200).step_by(3) {
+            tree.remove(&value);
+        }
+        let mut bytes = Vec::new();
+        tree.write_snapshot(&mut bytes).unwrap();
+        let back = Tree::<u64>::read_snapshot(bytes.as_slice()).unwrap();
+        assert_valid(&back);
+        assert!(back.structurally_eq(&tree));
+        assert_eq!(back.empty, tree.empty); // free slots come back in the same order
+        assert_eq!(back.graph.len(), tree.graph.len());
+
+        let empty: Tree<u64> = Tree::new();
+        assert!(corrupted(&empty, |_| {}).unwrap().is_empty());
+    }
+
+    #[test]
+    fn snapshot_rejects_corruption() {
+        // Slots 0, 1 and 2 hold 2 (black root), 1 (red, left) and 3 (red, right)
+        let tree = inserted(&[2, 1, 3]);
+        assert!(corrupted(&tree, |_| {}).unwrap().structurally_eq(&tree));
+
+        // Cut off anywhere, including in the middle of a value
+        let mut bytes = Vec::new();
+        tree.write_snapshot(&mut bytes).unwrap();
+        for len in 0..bytes.len() {
+            assert!(Tree::<u64>::read_snapshot(&bytes[..len]).is_err(), "accepted {} of {} bytes", len, bytes.len());
+        }
+
+        assert_invalid(corrupted(&tree, |b| b[0] = b'X'));
+        assert_invalid(corrupted(&tree, |b| b[4] = 2)); // a version this build doesn't know
+        assert_invalid(corrupted(&tree, |b| b[32] = 0b100)); // unknown slot flags
+        // Child indices past the last slot
+        assert_invalid(corrupted(&tree, |b| set_edge(b, 0, 1, 3)));
+        assert_invalid(corrupted(&tree, |b| set_edge(b, 0, 2, 1000)));
+        // The left child claims the right child as its parent
+        assert_invalid(corrupted(&tree, |b| set_edge(b, 1, 0, 2)));
+        // The root no longer points to its left child, which still points to it
+        assert_invalid(corrupted(&tree, |b| set_edge(b, 0, 1, u64::MAX)));
+        // Both children point at the same node
+        assert_invalid(corrupted(&tree, |b| set_edge(b, 0, 2, 1)));
+        // A black left leaf makes the black heights differ
+        assert_invalid(corrupted(&tree, |b| b[32 + 33] = 0b01));
+        // A red root
+        assert_invalid(corrupted(&tree, |b| b[32] = 0b11));
+        // Values out of order, the left child larger than the root
+        assert_invalid(corrupted(&tree, |b| b[32 + 33 + 25] = 9));
+    }
+
+    #[test]
+    fn extract_if_dropped_part_way() {
+        for seed in 1..60 {
+            let mut rng = Rng(seed * 797);
+            let mut tree: Tree<u64> = (0..rng.below(300)).map(|_| rng.below(400)).collect();
+            let mut reference: BTreeSet<u64> = tree.iter().copied().collect();
+            let modulus = rng.below(4) + 2;
+            let take = rng.below(20) as usize;
+            let mut seen = Vec::new();
+            let taken: Vec<u64> = tree
+                .extract_if(|x| {
+                    seen.push(*x);
+                    x % modulus == 0
+                })
+                .take(take)
+                .collect();
+            // Only the matches it got to are gone, everything past the last visited element stays
+            let expected: Vec<u64> = reference.iter().copied().filter(|x| x % modulus == 0).take(take).collect();
+            assert_eq!(taken, expected);
+            assert!(seen.windows(2).all(|w| w[0] < w[1]));
+            assert!(seen.iter().all(|x| reference.contains(x)));
+            for x in &taken {
+                reference.remove(x);
+            }
+            assert_valid(&tree);
+            assert!(tree.iter().eq(reference.iter()));
+        }
+
+        // Dropped without being polled, nothing is removed
+        let mut tree: Tree<u64> = (0..50).collect();
+        let _ = tree.extract_if(|_| true);
+        assert_eq!(tree.len(), 50);
+        assert!(tree.extract_if(|x| x % 2 == 1).eq((1..50).step_by(2)));
+        assert_valid(&tree);
+
+        // The conversions to and from BTreeSet go both ways without losing anything
+        let set: BTreeSet<u64> = tree.iter().copied().collect();
+        let back = Tree::from(set.clone());
+        assert_valid(&back);
+        assert_eq!(BTreeSet::from(&back), set);
+        assert_eq!(BTreeSet::from(back), set);
+    }
+
+    #[test]
+    fn into_sorted_vec_moves_every_element_once() {
+        let live = Rc::new(Cell::new(0));
+        let mut rng = Rng(798);
+        let mut tree = Tree::new();
+        for _ in 0..300 {
+            tree.insert(Counted::new(rng.below(500), &live));
+        }
+        for _ in 0..100 { // freed slots must be skipped, not read
+            tree.remove(&Counted::new(rng.below(500), &live));
+        }
+        let len = tree.len();
+        let expected: Vec<u64> = tree.iter().map(|c| c.value).collect();
+        let v = tree.into_sorted_vec();
+        assert_eq!(live.get(), len); // moved, not cloned or dropped
+        assert!(v.iter().map(|c| c.value).eq(expected.iter().copied()));
+        assert!(v.windows(2).all(|w| w[0].value < w[1].value));
+        drop(v);
+        assert_eq!(live.get(), 0);
+
+        let tree: Tree<u64> = Tree::from([3, 1, 2]);
+        assert_eq!(tree.to_vec(), vec![1, 2, 3]);
+        assert_eq!(tree.len(), 3); // to_vec only clones
+        assert_eq!(tree.into_sorted_vec(), vec![1, 2, 3]);
+        assert!(Tree::<u64>::new().into_sorted_vec().is_empty());
+    }
+
+    // Two random sets of very different or similar sizes, so the set operations are run both as
+    // merges and as searches of the larger tree, including empty operands
+    fn random_set_pair(rng: &mut Rng) -> (BTreeSet<u64>, BTreeSet<u64>) {
+        let sizes = [0, 1, 3, 20, 100, 600];
+        let set = |rng: &mut Rng| -> BTreeSet<u64> {
+            let size = sizes[rng.below(sizes.len() as u64) as usize];
+            let spread = 1 + size * (rng.below(3) + 1);
+            (0..size).map(|_| rng.below(spread)).collect()
+        };
+        (set(rng), set(rng))
+    }
+
+    #[test]
+    fn union_matches_btreeset() {
+        let mut rng = Rng(799);
+        for _ in 0..300 {
+            let (a, b) = random_set_pair(&mut rng);
+            let (ta, tb) = (Tree::from(a.clone()), Tree::from(b.clone()));
+            assert!(ta.union(&tb).eq(a.union(&b)));
+            assert!(tb.union(&ta).eq(b.union(&a)));
+            let mut merged = ta.clone();
+            merged.union_with(&tb);
+            assert_valid(&merged);
+            assert!(merged.iter().eq(a.union(&b)));
+        }
+        // Disjoint, and one a subset of the other
+        let (low, high): (Tree<u64>, Tree<u64>) = ((0..10).collect(), (10..20).collect());
+        assert!(low.union(&high).copied().eq(0..20));
+        let part: Tree<u64> = (5..8).collect();
+        assert!(low.union(&part).eq(low.iter()));
+    }
+
+    #[test]
+    fn intersection_matches_btreeset() {
+        let mut rng = Rng(800);
+        for _ in 0..300 {
+            let (a, b) = random_set_pair(&mut rng);
+            let (ta, tb) = (Tree::from(a.clone()), Tree::from(b.clone()));
+            assert!(ta.intersection(&tb).eq(a.intersection(&b)));
+            assert!(tb.intersection(&ta).eq(b.intersection(&a)));
+            let mut kept = ta.clone();
+            kept.intersect_with(&tb);
+            assert_valid(&kept);
+            assert!(kept.iter().eq(a.intersection(&b)));
+        }
+        let (low, high): (Tree<u64>, Tree<u64>) = ((0..10).collect(), (10..20).collect());
+        assert_eq!(low.intersection(&high).next(), None);
+        assert!(low.intersection(&low).eq(low.iter()));
+        // A small tree against a huge one probes the huge one instead of walking it
+        let huge: Tree<u64> = (0..100_000).map(|x| x * 2).collect();
+        let small: Tree<u64> = Tree::from([1, 2, 3, 4, 199_998, 200_000]);
+        assert!(small.intersection(&huge).eq([2, 4, 199_998].iter()));
+        assert!(huge.intersection(&small).eq([2, 4, 199_998].iter()));
+    }
+
+    #[test]
+    fn intersection_takes_elements_from_self() {
+        // Counted compares by value only, its counter tells which tree an element came from
+        let (in_a, in_b) = (Rc::new(Cell::new(0)), Rc::new(Cell::new(0)));
+        for (a_len, b_len) in [(10, 1000), (1000, 10), (500, 500)] { // both lookup orders and a merge
+            let a: Tree<Counted> = (0..a_len).map(|x| Counted::new(x * 3, &in_a)).collect();
+            let b: Tree<Counted> = (0..b_len).map(|x| Counted::new(x * 2, &in_b)).collect();
+            let common: Vec<u64> = (0..(a_len * 3).min(b_len * 2)).filter(|x| x % 6 == 0).collect();
+            assert!(a.intersection(&b).map(|c| c.value).eq(common.iter().copied()));
+            assert!(a.intersection(&b).all(|c| Rc::ptr_eq(&c.live, &in_a)));
+            assert!(b.intersection(&a).all(|c| Rc::ptr_eq(&c.live, &in_b)));
+        }
+    }
+
+    #[test]
+    fn difference_matches_btreeset() {
+        let mut rng = Rng(801);
+        for _ in 0..300 {
+            let (a, b) = random_set_pair(&mut rng);
+            let (ta, tb) = (Tree::from(a.clone()), Tree::from(b.clone()));
+            assert!(ta.difference(&tb).eq(a.difference(&b)));
+            assert!(tb.difference(&ta).eq(b.difference(&a)));
+            let mut left = ta.clone();
+            left.difference_with(&tb);
+            assert_valid(&left);
+            assert!(left.iter().eq(a.difference(&b)));
+
+            // What only a has, what only b has and what they share put both sets back together
+            let mut rebuilt_a: BTreeSet<u64> = ta.difference(&tb).copied().collect();
+            rebuilt_a.extend(ta.intersection(&tb));
+            let mut rebuilt_b: BTreeSet<u64> = tb.difference(&ta).copied().collect();
+            rebuilt_b.extend(ta.intersection(&tb));
+            assert_eq!((rebuilt_a, rebuilt_b), (a, b));
+        }
+        let tree: Tree<u64> = (0..10).collect();
+        assert!(tree.difference(&Tree::new()).eq(tree.iter()));
+        assert_eq!(Tree::new().difference(&tree).next(), None);
+        assert_eq!(tree.difference(&tree).next(), None);
+    }
+
+    #[test]
+    fn symmetric_difference_matches_btreeset() {
+        let mut rng = Rng(802);
+        for _ in 0..300 {
+            let (a, b) = random_set_pair(&mut rng);
+            let (ta, tb) = (Tree::from(a.clone()), Tree::from(b.clone()));
+            assert!(ta.symmetric_difference(&tb).eq(a.symmetric_difference(&b)));
+            assert!(tb.symmetric_difference(&ta).eq(b.symmetric_difference(&a)));
+        }
+        let tree: Tree<u64> = (0..10).collect();
+        assert_eq!(tree.symmetric_difference(&tree.clone()).next(), None);
+        assert!(tree.symmetric_difference(&Tree::new()).eq(tree.iter()));
+        assert!(Tree::new().symmetric_difference(&tree).eq(tree.iter()));
+        // Runs of shared elements at the point where the two walks meet are skipped together
+        let other: Tree<u64> = (5..15).collect();
+        assert!(tree.symmetric_difference(&other).copied().eq((0..5).chain(10..15)));
+    }
+
+    #[test]
+    fn merge_tags_each_side() {
+        let mut rng = Rng(805);
+        for _ in 0..300 {
+            let (a, b) = random_set_pair(&mut rng);
+            let (ta, tb) = (Tree::from_sorted_iter(a.iter().copied()), Tree::from_sorted_iter(b.iter().copied()));
+            let expected: Vec<MergeItem<u64>> = a
+                .union(&b)
+                .map(|x| match (a.get(x), b.get(x)) {
+                    (Some(l), Some(r)) => MergeItem::Both(l, r),
+                    (Some(l), None) => MergeItem::Left(l),
+                    _ => MergeItem::Right(x),
+                })
+                .collect();
+            assert_eq!(ta.merge(&tb).collect::<Vec<_>>(), expected);
+        }
+        // Values shared by both trees come out once as Both, with the left tree's copy first
+        let (in_a, in_b) = (Rc::new(Cell::new(0)), Rc::new(Cell::new(0)));
+        let left: Tree<Counted> = (0..30).map(|x| Counted::new(x * 2, &in_a)).collect();
+        let right: Tree<Counted> = (0..20).map(|x| Counted::new(x * 3, &in_b)).collect();
+        let mut seen: Vec<u64> = Vec::new();
+        for item in left.merge(&right) {
+            match item {
+                MergeItem::Left(x) => {
+                    assert_ne!(x.value % 3, 0);
+                    assert!(Rc::ptr_eq(&x.live, &in_a));
+                    seen.push(x.value);
+                }
+                MergeItem::Right(y) => {
+                    assert_ne!(y.value % 2, 0);
+                    assert!(Rc::ptr_eq(&y.live, &in_b));
+                    seen.push(y.value);
+                }
+                MergeItem::Both(x, y) => {
+                    assert_eq!((x.value % 6, x.value), (0, y.value));
+                    assert!(Rc::ptr_eq(&x.live, &in_a) && Rc::ptr_eq(&y.live, &in_b));
+                    seen.push(x.value);
+                }
+            }
+        }
+        let all: BTreeSet<u64> = (0..30).map(|x| x * 2).chain((0..20).map(|x| x * 3)).collect();
+        assert!(seen.iter().eq(all.iter())); // ascending, every value once
+    }
+
+    #[test]
+    fn subset_superset_disjoint_match_btreeset() {
+        let mut rng = Rng(803);
+        for _ in 0..500 {
+            let (a, mut b) = random_set_pair(&mut rng);
+            if rng.below(3) == 0 {
+                b.extend(a.iter().copied()); // make subsets common enough to matter
+            }
+            let (ta, tb) = (Tree::from(a.clone()), Tree::from(b.clone()));
+            assert_eq!(ta.is_subset(&tb), a.is_subset(&b));
+            assert_eq!(tb.is_subset(&ta), b.is_subset(&a));
+            assert_eq!(ta.is_superset(&tb), a.is_superset(&b));
+            assert_eq!(tb.is_superset(&ta), b.is_superset(&a));
+            assert_eq!(ta.is_disjoint(&tb), a.is_disjoint(&b));
+        }
+        let empty: Tree<u64> = Tree::new();
+        let tree: Tree<u64> = (0..10).collect();
+        assert!(empty.is_subset(&tree) && empty.is_subset(&empty) && tree.is_subset(&tree));
+        assert!(!tree.is_subset(&empty) && tree.is_superset(&empty));
+        assert!(empty.is_disjoint(&empty) && tree.is_disjoint(&empty));
+    }
+
+    #[test]
+    fn set_operators_match_btreeset() {
+        let mut rng = Rng(804);
+        for _ in 0..200 {
+            let (a, b) = random_set_pair(&mut rng);
+            let (ta, tb) = (Tree::from(a.clone()), Tree::from(b.clone()));
+            for (tree, set) in [(&ta | &tb, &a | &b), (&ta & &tb, &a & &b), (&ta - &tb, &a - &b), (&ta ^ &tb, &a ^ &b)] {
+                assert_valid(&tree);
+                assert!(tree.iter().eq(set.iter()));
+            }
+        }
+        // The operands are only borrowed
+        let (a, b): (Tree<u64>, Tree<u64>) = (Tree::from([1, 2, 3]), Tree::from([3, 4]));
+        assert_eq!((&a | &b).to_vec(), vec![1, 2, 3, 4]);
+        assert_eq!((&a & &b).to_vec(), vec![3]);
+        assert_eq!((&a - &b).to_vec(), vec![1, 2]);
+        assert_eq!((&a ^ &b).to_vec(), vec![1, 2, 4]);
+        assert_eq!((a.len(), b.len()), (3, 2));
+    }
+
+    #[test]
+    fn remove_all_counts_and_keeps_the_tree_valid() {
+        for seed in 1..100 {
+            let mut rng = Rng(seed * 806);
+            let mut tree: Tree<u64> = (0..rng.below(400)).map(|_| rng.below(500)).collect();
+            let mut reference: BTreeSet<u64> = tree.iter().copied().collect();
+            // Keys both present and missing, with repeats
+            let keys: Vec<u64> = (0..rng.below(300)).map(|_| rng.below(600)).collect();
+            let expected = keys.iter().filter(|k| reference.remove(*k)).count();
+            assert_eq!(tree.remove_all(&keys), expected);
+            assert_valid(&tree); // a repeated key freeing its slot twice would break the slot count
+            assert!(tree.iter().eq(reference.iter()));
+            assert_eq!(tree.remove_all(&keys), 0);
+        }
+
+        // Removing from either end one element at a time
+        let mut rng = Rng(8060);
+        let mut tree: Tree<u64> = (0..500).map(|_| rng.below(2000)).collect();
+        let mut reference: BTreeSet<u64> = tree.iter().copied().collect();
+        while !tree.is_empty() {
+            if rng.below(2) == 0 {
+                assert!(tree.remove_min());
+                reference.pop_first();
+            } else {
+                assert!(tree.remove_max());
+                reference.pop_last();
+            }
+            assert_valid(&tree);
+            assert!(tree.iter().eq(reference.iter()));
+        }
+        assert!(!tree.remove_min() && !tree.remove_max());
+    }
+
+    #[test]
+    fn in_place_set_operations() {
+        // Operands much smaller than the tree, similar and much larger, so both the one at a
+        // time and the whole-tree paths of each operation run
+        for other_size in [0, 3, 40, 400, 4000] {
+            let mut rng = Rng(808 + other_size);
+            let base: Tree<u64> = (0..400).map(|_| rng.below(800)).collect();
+            let other: Tree<u64> = (0..other_size).map(|_| rng.below(800)).collect();
+            let (a, b): (BTreeSet<u64>, BTreeSet<u64>) = (base.iter().copied().collect(), other.iter().copied().collect());
+
+            let mut tree = base.clone();
+            tree.union_with(&other);
+            assert_valid(&tree);
+            assert!(tree.iter().eq(a.union(&b)));
+
+            let mut tree = base.clone();
+            tree.intersect_with(&other);
+            assert_valid(&tree);
+            assert!(tree.iter().eq(a.intersection(&b)));
+
+            let mut tree = base.clone();
+            tree.difference_with(&other);
+            assert_valid(&tree);
+            assert!(tree.iter().eq(a.difference(&b)));
         }
-        ll
     }
 }
@@ -0,0 +1,878 @@
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::iter::FusedIterator;
+use std::ops::RangeBounds;
+
+use crate::rbt::{self, Tree};
+
+/// An ordered map from keys to values, built on the same red-black tree as `Tree`. Each node
+/// stores a key and its value, and only the key decides where the node goes, so every insert,
+/// removal and rebalance is the set's own code running on key-value pairs
+pub struct TreeMap<K, V> {
+    tree: Tree<KeyValue<K, V>>,
+}
+
+// What the map stores in each node of its tree. Comparisons only look at the key, and borrowing
+// the pair as a K lets the tree's own lookups take a plain &K
+struct KeyValue<K, V> {
+    key: K,
+    value: V,
+}
+
+impl<K: std::cmp::PartialOrd, V> PartialEq for KeyValue<K, V> {
+    fn eq(&self, other: &KeyValue<K, V>) -> bool {
+        self.key == other.key
+    }
+}
+
+impl<K: std::cmp::PartialOrd, V> PartialOrd for KeyValue<K, V> {
+    fn partial_cmp(&self, other: &KeyValue<K, V>) -> Option<Ordering> {
+        self.key.partial_cmp(&other.key)
+    }
+}
+
+impl<K, V> Borrow<K> for KeyValue<K, V> {
+    fn borrow(&self) -> &K {
+        &self.key
+    }
+}
+
+impl<K: std::cmp::PartialOrd, V> TreeMap<K, V> {
+    /// Creates a new, empty map
+    pub const fn new() -> TreeMap<K, V> {
+        TreeMap { tree: Tree::new() }
+    }
+
+    /// Number of entries in the map
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    /// Returns true if the map has no entries
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// Inserts value under key. If key was already in the map its value is swapped in place and
+    /// the old value returned, keeping the key that was stored. That only writes to the value,
+    /// so the edges and colors of the tree are left exactly as they were. Otherwise the entry is
+    /// added and None returned
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        match self.tree.find_slot_borrowed(&key) {
+            Ok(idx) => Some(std::mem::replace(&mut self.tree.value_mut(idx).value, value)),
+            Err((parent, is_left)) => {
+                self.tree.insert_at(parent, is_left, KeyValue { key, value });
+                None
+            }
+        }
+    }
+
+    /// Returns a reference to the value stored under key
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.tree.get(key).map(|kv| &kv.value)
+    }
+
+    /// Returns a mutable reference to the value stored under key. The key itself can't be
+    /// changed this way, so the entry stays where it is in the tree
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let idx = self.tree.find_index(key)?;
+        Some(&mut self.tree.value_mut(idx).value)
+    }
+
+    /// Returns true if there is a value stored under key
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.tree.contains(key)
+    }
+
+    /// Removes key from the map and returns the value that was stored under it
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.tree.remove(key).map(|kv| kv.value)
+    }
+
+    /// Returns the entry with the smallest key, or None if the map is empty
+    pub fn first_key_value(&self) -> Option<(&K, &V)> {
+        self.tree.first().map(|kv| (&kv.key, &kv.value))
+    }
+
+    /// Returns the entry with the largest key, or None if the map is empty
+    pub fn last_key_value(&self) -> Option<(&K, &V)> {
+        self.tree.last().map(|kv| (&kv.key, &kv.value))
+    }
+
+    /// Removes the entry with the smallest key and returns it, or None if the map is empty.
+    /// Goes straight down the left edge to it, so popping in a loop drains the map in
+    /// ascending key order like a job queue
+    pub fn pop_first(&mut self) -> Option<(K, V)> {
+        self.tree.pop_min().map(|kv| (kv.key, kv.value))
+    }
+
+    /// Removes the entry with the largest key and returns it, or None if the map is empty
+    pub fn pop_last(&mut self) -> Option<(K, V)> {
+        self.tree.pop_max().map(|kv| (kv.key, kv.value))
+    }
+
+    /// Moves every entry of other into this map, leaving other empty. Where both maps have a
+    /// key the value from other replaces the one in this map. Like `Tree::append` the two
+    /// are merged in sorted order and rebuilt in O(n + m)
+    pub fn append(&mut self, other: &mut TreeMap<K, V>) {
+        // The set keeps the element already in the tree it appends to, so merge into other and
+        // hand the result back
+        other.tree.append(&mut self.tree);
+        std::mem::swap(&mut self.tree, &mut other.tree);
+    }
+
+    /// Splits the map in two at key. Entries whose keys are greater than or equal to key are
+    /// moved into the returned map and the smaller ones stay in this one
+    pub fn split_off(&mut self, key: &K) -> TreeMap<K, V> {
+        TreeMap { tree: self.tree.split_off_borrowed(key) }
+    }
+
+    /// Keeps only the entries for which f returns true. f is called once per entry in ascending
+    /// key order and may change the value whether or not the entry stays
+    pub fn retain<F: FnMut(&K, &mut V) -> bool>(&mut self, f: F) {
+        let mut f = f;
+        self.tree.retain_mut(|kv| f(&kv.key, &mut kv.value));
+    }
+
+    /// Looks up key and returns its entry, which can be read, changed or filled in without
+    /// searching again. A vacant entry remembers where key belongs, so inserting into it
+    /// attaches the new node there and rebalances from it instead of descending from the root
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V> {
+        match self.tree.find_slot_borrowed(&key) {
+            Ok(idx) => Entry::Occupied(OccupiedEntry { tree: &mut self.tree, idx }),
+            Err((parent, is_left)) => Entry::Vacant(VacantEntry { tree: &mut self.tree, key, parent, is_left }),
+        }
+    }
+
+    /// Iterates over the entries in ascending order of their keys
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter { inner: self.tree.iter() }
+    }
+
+    /// Iterates over the entries in ascending key order with mutable access to the values
+    pub fn iter_mut(&mut self) -> IterMut<'_, K, V> {
+        IterMut { inner: self.tree.iter_mut() }
+    }
+
+    /// Iterates over the entries whose keys fall within range, in ascending key order. The first
+    /// and last entries in range are found with a descent each, so the iterator starts in
+    /// O(log n) however far into the map the range begins
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Range<'_, K, V> {
+        Range { inner: self.tree.range_borrowed(range) }
+    }
+
+    /// Iterates over the entries whose keys fall within range with mutable access to the values
+    pub fn range_mut<R: RangeBounds<K>>(&mut self, range: R) -> RangeMut<'_, K, V> {
+        RangeMut { inner: self.tree.range_mut_borrowed(range) }
+    }
+
+    /// Iterates over the keys in ascending order
+    pub fn keys(&self) -> Keys<'_, K, V> {
+        Keys { inner: self.tree.iter() }
+    }
+
+    /// Iterates over the values in ascending order of their keys
+    pub fn values(&self) -> Values<'_, K, V> {
+        Values { inner: self.tree.iter() }
+    }
+
+    /// Iterates over mutable references to the values in ascending order of their keys
+    pub fn values_mut(&mut self) -> ValuesMut<'_, K, V> {
+        ValuesMut { inner: self.tree.iter_mut() }
+    }
+}
+
+impl<K: std::cmp::PartialOrd, V> Default for TreeMap<K, V> {
+    fn default() -> TreeMap<K, V> {
+        TreeMap::new()
+    }
+}
+
+impl<K: std::cmp::PartialOrd + fmt::Debug, V: fmt::Debug> fmt::Debug for TreeMap<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<'a, K: std::cmp::PartialOrd, V> IntoIterator for &'a TreeMap<K, V> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+impl<'a, K: std::cmp::PartialOrd, V> IntoIterator for &'a mut TreeMap<K, V> {
+    type Item = (&'a K, &'a mut V);
+    type IntoIter = IterMut<'a, K, V>;
+
+    fn into_iter(self) -> IterMut<'a, K, V> {
+        self.iter_mut()
+    }
+}
+
+/// Collects key-value pairs into a map. When a key comes up more than once the last value
+/// given for it is the one kept, as with repeated calls to `insert`
+impl<K: std::cmp::PartialOrd, V> FromIterator<(K, V)> for TreeMap<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> TreeMap<K, V> {
+        let mut map = TreeMap::new();
+        map.extend(iter);
+        map
+    }
+}
+
+/// Inserts every pair of the iterator, reserving space for its lower size hint first. Values
+/// for keys already in the map are replaced
+impl<K: std::cmp::PartialOrd, V> Extend<(K, V)> for TreeMap<K, V> {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        self.tree.reserve(iter.size_hint().0);
+        for (key, value) in iter {
+            self.insert(key, value);
+        }
+    }
+}
+
+/// Copies the pairs in, so one map can be extended from another's `iter()`
+impl<'a, K: std::cmp::PartialOrd + Copy, V: Copy> Extend<(&'a K, &'a V)> for TreeMap<K, V> {
+    fn extend<I: IntoIterator<Item = (&'a K, &'a V)>>(&mut self, iter: I) {
+        self.extend(iter.into_iter().map(|(key, value)| (*key, *value)));
+    }
+}
+
+/// A view into a single key of a map, created by `TreeMap::entry`
+pub enum Entry<'a, K, V> {
+    /// The key is in the map
+    Occupied(OccupiedEntry<'a, K, V>),
+    /// The key is not in the map
+    Vacant(VacantEntry<'a, K, V>),
+}
+
+/// An entry whose key is in the map
+pub struct OccupiedEntry<'a, K, V> {
+    tree: &'a mut Tree<KeyValue<K, V>>,
+    idx: usize, // slot the entry is stored in
+}
+
+/// An entry whose key is not in the map, holding on to the key and the spot it belongs in
+pub struct VacantEntry<'a, K, V> {
+    tree: &'a mut Tree<KeyValue<K, V>>,
+    key: K,
+    parent: Option<usize>, // node the key would hang off of, None in an empty map
+    is_left: bool,
+}
+
+impl<'a, K: std::cmp::PartialOrd, V> Entry<'a, K, V> {
+    /// Returns the entry's value, inserting default first if the entry is vacant
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default),
+        }
+    }
+
+    /// Returns the entry's value, inserting the result of f first if the entry is vacant.
+    /// f is only called when the value is needed
+    pub fn or_insert_with<F: FnOnce() -> V>(self, f: F) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(f()),
+        }
+    }
+
+    /// Returns the entry's value, inserting `V::default()` first if the entry is vacant
+    pub fn or_default(self) -> &'a mut V
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+
+    /// Calls f on the value if the entry is occupied, then hands the entry back so it can be
+    /// chained with one of the or_insert methods
+    pub fn and_modify<F: FnOnce(&mut V)>(self, f: F) -> Entry<'a, K, V> {
+        match self {
+            Entry::Occupied(mut entry) => {
+                f(entry.get_mut());
+                Entry::Occupied(entry)
+            }
+            Entry::Vacant(entry) => Entry::Vacant(entry),
+        }
+    }
+
+    /// The key the entry was looked up with, or the one already stored for an occupied entry
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Occupied(entry) => entry.key(),
+            Entry::Vacant(entry) => entry.key(),
+        }
+    }
+}
+
+impl<'a, K: std::cmp::PartialOrd, V> OccupiedEntry<'a, K, V> {
+    /// The key stored in the map
+    pub fn key(&self) -> &K {
+        &self.tree.value(self.idx).key
+    }
+
+    /// Returns a reference to the value
+    pub fn get(&self) -> &V {
+        &self.tree.value(self.idx).value
+    }
+
+    /// Returns a mutable reference to the value, borrowed from the entry
+    pub fn get_mut(&mut self) -> &mut V {
+        &mut self.tree.value_mut(self.idx).value
+    }
+
+    /// Turns the entry into a mutable reference to the value that lives as long as the map borrow
+    pub fn into_mut(self) -> &'a mut V {
+        &mut self.tree.value_mut(self.idx).value
+    }
+
+    /// Replaces the value and returns the old one
+    pub fn insert(&mut self, value: V) -> V {
+        std::mem::replace(self.get_mut(), value)
+    }
+
+    /// Removes the entry from the map and returns its value
+    pub fn remove(self) -> V {
+        self.remove_entry().1
+    }
+
+    /// Removes the entry from the map and returns its key and value
+    pub fn remove_entry(self) -> (K, V) {
+        let kv = self.tree.remove_index(self.idx);
+        (kv.key, kv.value)
+    }
+}
+
+impl<'a, K: std::cmp::PartialOrd, V> VacantEntry<'a, K, V> {
+    /// The key the entry was looked up with
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Gives the key back without inserting anything
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    /// Inserts value under the entry's key and returns a mutable reference to it. The node is
+    /// attached where the lookup ended and rebalancing never moves it to another slot, so the
+    /// reference points at the value wherever the rotations leave it
+    pub fn insert(self, value: V) -> &'a mut V {
+        let idx = self.tree.insert_at(self.parent, self.is_left, KeyValue { key: self.key, value });
+        &mut self.tree.value_mut(idx).value
+    }
+}
+
+/// Iterator over the entries of a map in ascending key order, created by `TreeMap::iter`
+pub struct Iter<'a, K, V> {
+    inner: rbt::Iter<'a, KeyValue<K, V>>,
+}
+
+impl<'a, K: std::cmp::PartialOrd, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        self.inner.next().map(|kv| (&kv.key, &kv.value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K: std::cmp::PartialOrd, V> DoubleEndedIterator for Iter<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(|kv| (&kv.key, &kv.value))
+    }
+}
+
+impl<K: std::cmp::PartialOrd, V> ExactSizeIterator for Iter<'_, K, V> {}
+
+impl<K: std::cmp::PartialOrd, V> FusedIterator for Iter<'_, K, V> {}
+
+/// Iterator over the entries of a map in ascending key order with mutable values, created by
+/// `TreeMap::iter_mut`. Keys are only ever lent out immutably so the order can't be broken
+pub struct IterMut<'a, K, V> {
+    inner: rbt::IterMut<'a, KeyValue<K, V>>,
+}
+
+impl<'a, K, V> Iterator for IterMut<'a, K, V> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a mut V)> {
+        self.inner.next().map(|kv| (&kv.key, &mut kv.value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K, V> DoubleEndedIterator for IterMut<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(|kv| (&kv.key, &mut kv.value))
+    }
+}
+
+impl<K, V> ExactSizeIterator for IterMut<'_, K, V> {}
+
+impl<K, V> FusedIterator for IterMut<'_, K, V> {}
+
+/// Iterator over the entries of a map with keys in a range, created by `TreeMap::range`
+pub struct Range<'a, K, V> {
+    inner: rbt::Range<'a, KeyValue<K, V>>,
+}
+
+impl<'a, K: std::cmp::PartialOrd, V> Iterator for Range<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        self.inner.next().map(|kv| (&kv.key, &kv.value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K: std::cmp::PartialOrd, V> DoubleEndedIterator for Range<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(|kv| (&kv.key, &kv.value))
+    }
+}
+
+impl<K: std::cmp::PartialOrd, V> ExactSizeIterator for Range<'_, K, V> {}
+
+impl<K: std::cmp::PartialOrd, V> FusedIterator for Range<'_, K, V> {}
+
+/// Iterator over the entries of a map with keys in a range, with mutable values, created by
+/// `TreeMap::range_mut`
+pub struct RangeMut<'a, K, V> {
+    inner: rbt::IterMut<'a, KeyValue<K, V>>,
+}
+
+impl<'a, K, V> Iterator for RangeMut<'a, K, V> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a mut V)> {
+        self.inner.next().map(|kv| (&kv.key, &mut kv.value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K, V> DoubleEndedIterator for RangeMut<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(|kv| (&kv.key, &mut kv.value))
+    }
+}
+
+impl<K, V> ExactSizeIterator for RangeMut<'_, K, V> {}
+
+impl<K, V> FusedIterator for RangeMut<'_, K, V> {}
+
+/// Iterator over the keys of a map in ascending order, created by `TreeMap::keys`
+pub struct Keys<'a, K, V> {
+    inner: rbt::Iter<'a, KeyValue<K, V>>,
+}
+
+impl<'a, K: std::cmp::PartialOrd, V> Iterator for Keys<'a, K, V> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<&'a K> {
+        self.inner.next().map(|kv| &kv.key)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K: std::cmp::PartialOrd, V> DoubleEndedIterator for Keys<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(|kv| &kv.key)
+    }
+}
+
+impl<K: std::cmp::PartialOrd, V> ExactSizeIterator for Keys<'_, K, V> {}
+
+impl<K: std::cmp::PartialOrd, V> FusedIterator for Keys<'_, K, V> {}
+
+/// Iterator over the values of a map in ascending key order, created by `TreeMap::values`
+pub struct Values<'a, K, V> {
+    inner: rbt::Iter<'a, KeyValue<K, V>>,
+}
+
+impl<'a, K: std::cmp::PartialOrd, V> Iterator for Values<'a, K, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<&'a V> {
+        self.inner.next().map(|kv| &kv.value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K: std::cmp::PartialOrd, V> DoubleEndedIterator for Values<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(|kv| &kv.value)
+    }
+}
+
+impl<K: std::cmp::PartialOrd, V> ExactSizeIterator for Values<'_, K, V> {}
+
+impl<K: std::cmp::PartialOrd, V> FusedIterator for Values<'_, K, V> {}
+
+/// Iterator over mutable references to the values of a map in ascending key order, created by
+/// `TreeMap::values_mut`
+pub struct ValuesMut<'a, K, V> {
+    inner: rbt::IterMut<'a, KeyValue<K, V>>,
+}
+
+impl<'a, K, V> Iterator for ValuesMut<'a, K, V> {
+    type Item = &'a mut V;
+
+    fn next(&mut self) -> Option<&'a mut V> {
+        self.inner.next().map(|kv| &mut kv.value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K, V> DoubleEndedIterator for ValuesMut<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(|kv| &mut kv.value)
+    }
+}
+
+impl<K, V> ExactSizeIterator for ValuesMut<'_, K, V> {}
+
+impl<K, V> FusedIterator for ValuesMut<'_, K, V> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::collections::btree_map::{self, BTreeMap};
+    use std::ops::Bound;
+    use std::rc::Rc;
+
+    // Same xorshift generator as the tree's tests
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    // Checks the tree under the map and that it holds the same entries as reference
+    fn assert_matches<V: PartialEq + fmt::Debug>(map: &TreeMap<u64, V>, reference: &BTreeMap<u64, V>) {
+        assert!(map.tree.is_valid_red_black() && map.tree.is_bst());
+        assert_eq!(map.tree.validate_parent_pointers(), Ok(()));
+        assert_eq!(map.len(), reference.len());
+        assert!(map.iter().eq(reference.iter()));
+    }
+
+    #[test]
+    fn random_operations_match_btreemap() {
+        for seed in 1..40 {
+            let mut rng = Rng(seed * 811);
+            let mut map: TreeMap<u64, u64> = TreeMap::new();
+            let mut reference: BTreeMap<u64, u64> = BTreeMap::new();
+            for step in 0..600 {
+                let key = rng.below(150);
+                match rng.below(7) {
+                    0 | 1 => assert_eq!(map.insert(key, step), reference.insert(key, step)),
+                    2 => assert_eq!(map.remove(&key), reference.remove(&key)),
+                    3 => {
+                        if let (Some(v), Some(r)) = (map.get_mut(&key), reference.get_mut(&key)) {
+                            *v += 1;
+                            *r += 1;
+                        }
+                    }
+                    4 => {
+                        *map.entry(key).and_modify(|v| *v *= 2).or_insert(step) += 1;
+                        *reference.entry(key).and_modify(|v| *v *= 2).or_insert(step) += 1;
+                    }
+                    5 => match (map.entry(key), reference.entry(key)) {
+                        (Entry::Occupied(entry), btree_map::Entry::Occupied(r)) => {
+                            assert_eq!(entry.key(), r.key());
+                            assert_eq!(entry.remove_entry(), r.remove_entry());
+                        }
+                        (Entry::Vacant(entry), btree_map::Entry::Vacant(r)) => {
+                            assert_eq!(entry.key(), r.key());
+                            *entry.insert(step) += 1;
+                            *r.insert(step) += 1;
+                        }
+                        _ => panic!("entry for {} is occupied in only one of the maps", key),
+                    },
+                    _ => {
+                        *map.entry(key).or_default() += 3;
+                        *reference.entry(key).or_default() += 3;
+                    }
+                }
+                assert_eq!(map.get(&key), reference.get(&key));
+                assert_eq!(map.contains_key(&key), reference.contains_key(&key));
+                assert_matches(&map, &reference);
+            }
+        }
+    }
+
+    // Every key with its depth, color and side, enough to tell whether any node moved
+    fn shape<V>(map: &TreeMap<u64, V>) -> Vec<(u64, usize, rbt::Color, rbt::NodePosition)> {
+        map.tree.iter_annotated().map(|n| (n.value.key, n.depth, n.color, n.position)).collect()
+    }
+
+    #[test]
+    fn changing_values_leaves_the_shape_alone() {
+        let mut rng = Rng(812);
+        let mut map: TreeMap<u64, String> = TreeMap::new();
+        for _ in 0..300 {
+            let key = rng.below(1000);
+            map.insert(key, key.to_string());
+        }
+        for _ in 0..100 {
+            map.remove(&rng.below(1000));
+        }
+        let before = shape(&map);
+        let keys: Vec<u64> = map.keys().copied().collect();
+
+        for value in map.values_mut() {
+            value.push('!');
+        }
+        for (_, value) in map.iter_mut().rev() {
+            value.push('?');
+        }
+        for (_, value) in map.range_mut(100..500) {
+            value.insert(0, '<');
+        }
+        let first = keys[0];
+        map.get_mut(&first).unwrap().clear();
+        map.entry(keys[1]).or_default().push('#');
+        map.entry(keys[2]).and_modify(|v| v.push('@'));
+        assert_eq!(map.insert(keys[3], "replaced".to_string()), Some(format!("{}!?", keys[3])));
+        map.retain(|_, v| {
+            v.push('.');
+            true
+        });
+
+        assert_eq!(shape(&map), before);
+        assert!(map.keys().copied().eq(keys.iter().copied()));
+        assert_eq!(map.get(&first).map(String::as_str), Some("."));
+        assert_eq!(map.get(&keys[3]).map(String::as_str), Some("replaced."));
+    }
+
+    #[test]
+    fn first_and_last_entries() {
+        let mut empty: TreeMap<u64, u64> = TreeMap::new();
+        assert_eq!(empty.first_key_value(), None);
+        assert_eq!(empty.last_key_value(), None);
+        assert_eq!(empty.pop_first(), None);
+        assert_eq!(empty.pop_last(), None);
+
+        let mut single: TreeMap<u64, &str> = TreeMap::new();
+        single.insert(7, "seven");
+        assert_eq!(single.first_key_value(), Some((&7, &"seven")));
+        assert_eq!(single.last_key_value(), Some((&7, &"seven")));
+        assert_eq!(single.pop_last(), Some((7, "seven")));
+        assert!(single.is_empty());
+
+        let mut rng = Rng(813);
+        let mut map: TreeMap<u64, u64> = TreeMap::new();
+        let mut reference: BTreeMap<u64, u64> = BTreeMap::new();
+        for step in 0..400 {
+            let key = rng.below(500);
+            map.insert(key, step);
+            reference.insert(key, step);
+        }
+        while !reference.is_empty() {
+            assert_eq!(map.first_key_value(), reference.first_key_value());
+            assert_eq!(map.last_key_value(), reference.last_key_value());
+            if rng.below(2) == 0 {
+                assert_eq!(map.pop_first(), reference.pop_first());
+            } else {
+                assert_eq!(map.pop_last(), reference.pop_last());
+            }
+            assert_matches(&map, &reference);
+        }
+        assert_eq!(map.pop_first(), None);
+        assert_eq!(map.first_key_value(), None);
+    }
+
+    // BTreeMap::range panics on these instead of returning nothing
+    fn is_inverted(start: Bound<u64>, end: Bound<u64>) -> bool {
+        match (start, end) {
+            (Bound::Excluded(s), Bound::Excluded(e)) => s >= e,
+            (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e)) => s > e,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn range_matches_btreemap_for_every_bound() {
+        let mut rng = Rng(814);
+        let mut map: TreeMap<u64, u64> = TreeMap::new();
+        let mut reference: BTreeMap<u64, u64> = BTreeMap::new();
+        for _ in 0..40 {
+            let key = rng.below(30) * 2; // even keys, so odd bounds fall between entries
+            map.insert(key, key);
+            reference.insert(key, key);
+        }
+        // Bounds below, between, on and past the keys, of every kind
+        let mut bounds = vec![Bound::Unbounded];
+        for x in 0..62 {
+            bounds.push(Bound::Included(x));
+            bounds.push(Bound::Excluded(x));
+        }
+        for &start in &bounds {
+            for &end in &bounds {
+                if is_inverted(start, end) {
+                    assert_eq!(map.range((start, end)).next(), None);
+                    assert_eq!(map.range_mut((start, end)).next(), None);
+                    continue;
+                }
+                let expected: Vec<(&u64, &u64)> = reference.range((start, end)).collect();
+                assert!(map.range((start, end)).eq(expected.iter().copied()));
+                assert!(map.range((start, end)).rev().eq(expected.iter().rev().copied()));
+                assert_eq!(map.range((start, end)).len(), expected.len());
+            }
+        }
+
+        // range_mut reaches exactly the entries range would
+        for &(start, end) in &[(Bound::Included(10), Bound::Excluded(30)), (Bound::Excluded(9), Bound::Unbounded), (Bound::Unbounded, Bound::Included(0))] {
+            for (_, value) in map.range_mut((start, end)) {
+                *value += 1000;
+            }
+            for (_, value) in reference.range_mut((start, end)) {
+                *value += 1000;
+            }
+            assert_matches(&map, &reference);
+        }
+    }
+
+    // A value that counts how many of its kind are alive
+    struct Counted(Rc<Cell<usize>>);
+
+    impl Counted {
+        fn new(live: &Rc<Cell<usize>>) -> Counted {
+            live.set(live.get() + 1);
+            Counted(Rc::clone(live))
+        }
+    }
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() - 1);
+        }
+    }
+
+    #[test]
+    fn retain_drops_what_it_removes() {
+        let live = Rc::new(Cell::new(0));
+        let mut rng = Rng(815);
+        let mut map: TreeMap<u64, Counted> = TreeMap::new();
+        for _ in 0..500 {
+            map.insert(rng.below(400), Counted::new(&live)); // a replaced value is dropped by insert
+        }
+        assert_eq!(live.get(), map.len());
+        let mut reference: BTreeMap<u64, ()> = map.keys().map(|k| (*k, ())).collect();
+
+        let mut visited = Vec::new();
+        map.retain(|key, _| {
+            visited.push(*key);
+            key % 3 != 0
+        });
+        reference.retain(|key, _| key % 3 != 0);
+        assert!(visited.windows(2).all(|w| w[0] < w[1])); // each entry once, in key order
+        assert_eq!(live.get(), map.len()); // the removed values were dropped, the rest weren't
+        assert!(map.keys().eq(reference.keys()));
+        assert!(map.tree.is_valid_red_black() && map.tree.is_bst());
+
+        map.retain(|_, _| true);
+        assert_eq!(live.get(), reference.len());
+        map.retain(|_, _| false);
+        assert!(map.is_empty());
+        assert_eq!(live.get(), 0);
+    }
+
+    #[test]
+    fn collect_and_extend_keep_the_last_value() {
+        let pairs = [(3, "a"), (1, "b"), (3, "c"), (2, "d"), (1, "e"), (3, "f")];
+        let map: TreeMap<u64, &str> = pairs.iter().copied().collect();
+        let reference: BTreeMap<u64, &str> = pairs.iter().copied().collect();
+        assert_matches(&map, &reference);
+        assert_eq!(map.get(&3), Some(&"f"));
+
+        let mut rng = Rng(816);
+        let mut map: TreeMap<u64, u64> = TreeMap::new();
+        let mut reference: BTreeMap<u64, u64> = BTreeMap::new();
+        for round in 0..5 {
+            let batch: Vec<(u64, u64)> = (0..200).map(|i| (rng.below(150), round * 1000 + i)).collect();
+            map.extend(batch.iter().copied());
+            reference.extend(batch.iter().copied());
+            assert_matches(&map, &reference);
+        }
+
+        // Extending from another map's references copies its entries over, replacing values
+        let other: TreeMap<u64, u64> = (100..200).map(|k| (k, k)).collect();
+        map.extend(other.iter());
+        reference.extend((100..200).map(|k| (k, k)));
+        assert_matches(&map, &reference);
+        assert_eq!(other.len(), 100);
+    }
+
+    #[test]
+    fn append_and_split_off_match_btreemap() {
+        for seed in 1..30 {
+            let mut rng = Rng(seed * 817);
+            let mut map: TreeMap<u64, u64> = TreeMap::new();
+            let mut other: TreeMap<u64, u64> = TreeMap::new();
+            let mut reference: BTreeMap<u64, u64> = BTreeMap::new();
+            let mut reference_other: BTreeMap<u64, u64> = BTreeMap::new();
+            for i in 0..rng.below(200) {
+                let key = rng.below(300);
+                map.insert(key, i);
+                reference.insert(key, i);
+            }
+            for i in 0..rng.below(200) {
+                let key = rng.below(300);
+                other.insert(key, 1000 + i);
+                reference_other.insert(key, 1000 + i);
+            }
+            // Keys in both maps take other's value
+            map.append(&mut other);
+            reference.append(&mut reference_other);
+            assert!(other.is_empty());
+            assert_matches(&map, &reference);
+
+            // Present keys, absent keys and both ends
+            let first = reference.keys().next().copied().unwrap_or(0);
+            let last = reference.keys().next_back().copied().unwrap_or(0);
+            for at in [rng.below(300), 301, first, last, last + 1, 0] {
+                let mut low = TreeMap::new();
+                low.extend(map.iter());
+                let mut reference_low = reference.clone();
+                let high = low.split_off(&at);
+                let reference_high = reference_low.split_off(&at);
+                assert_matches(&low, &reference_low);
+                assert_matches(&high, &reference_high);
+            }
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use std::fmt;
+use std::marker::PhantomData;
+
+use serde::de::{Deserialize, Deserializer, SeqAccess, Visitor};
+use serde::ser::{Serialize, Serializer};
+
+use crate::rbt::Tree;
+
+/// Trees are serialized as a sequence of their elements in ascending order, so the output is the
+/// same however the nodes happen to be arranged
+impl<T: std::cmp::PartialOrd + Serialize> Serialize for Tree<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+/// Deserializing accepts any sequence. The elements are collected and handed to `Tree::from`,
+/// which sorts them and drops repeats before building the tree in O(n), so input written by
+/// `Serialize` (already sorted) skips straight to the build
+impl<'de, T: std::cmp::PartialOrd + Deserialize<'de>> Deserialize<'de> for Tree<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Tree<T>, D::Error> {
+        deserializer.deserialize_seq(TreeVisitor { marker: PhantomData })
+    }
+}
+
+struct TreeVisitor<T> {
+    marker: PhantomData<T>,
+}
+
+impl<'de, T: std::cmp::PartialOrd + Deserialize<'de>> Visitor<'de> for TreeVisitor<T> {
+    type Value = Tree<T>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a sequence")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Tree<T>, A::Error> {
+        // The hint comes from the input, so it is capped rather than trusted outright
+        let mut values = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+        while let Some(value) = seq.next_element()? {
+            values.push(value);
+        }
+        Ok(Tree::from(values))
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use crate::rbt::Tree;
+
+    #[test]
+    fn json_round_trip() {
+        let mut tree: Tree<String> = ["pear", "fig", "apple", "kiwi"].iter().map(|s| s.to_string()).collect();
+        tree.remove("kiwi"); // the freed slot must not leak into the output
+        let json = serde_json::to_string(&tree).unwrap();
+        assert_eq!(json, r#"["apple","fig","pear"]"#);
+        let back: Tree<String> = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, tree);
+        assert!(back.is_valid_red_black());
+
+        let empty: Tree<u32> = serde_json::from_str("[]").unwrap();
+        assert!(empty.is_empty());
+        assert_eq!(serde_json::to_string(&empty).unwrap(), "[]");
+        assert!(serde_json::from_str::<Tree<u32>>(r#"{"a": 1}"#).is_err());
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let tree: Tree<u64> = (0..1000).map(|x| x * 7919 % 1009).collect();
+        let bytes = bincode::serialize(&tree).unwrap();
+        let back: Tree<u64> = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(back, tree);
+        assert!(back.is_valid_red_black() && back.is_bst());
+        // A truncated buffer is an error, not a partial tree
+        assert!(bincode::deserialize::<Tree<u64>>(&bytes[..bytes.len() - 3]).is_err());
+    }
+
+    #[test]
+    fn unsorted_input_with_duplicates() {
+        let tree: Tree<i32> = serde_json::from_str("[5, -1, 3, 5, 9, -1, 0, 3]").unwrap();
+        assert_eq!(tree.to_vec(), vec![-1, 0, 3, 5, 9]);
+        assert_eq!(tree.len(), 5);
+        assert!(tree.is_valid_red_black() && tree.is_bst());
+        assert_eq!(tree.validate_parent_pointers(), Ok(()));
+    }
+}
@@ -0,0 +1,118 @@
+use std::io::{self, Read, Write};
+
+/// Values that can be stored in a snapshot written by `Tree::write_snapshot`. Implemented for the
+/// primitive numbers, bool, char and String. Numbers are little endian, and usize/isize are
+/// always written as 64 bits so a snapshot can be read on a machine with a different word size
+pub trait SnapshotValue: Sized {
+    /// Writes the value to w
+    fn write_value<W: Write>(&self, w: &mut W) -> io::Result<()>;
+
+    /// Reads back a value written by `write_value`, failing with `InvalidData` if the bytes
+    /// don't make a valid value
+    fn read_value<R: Read>(r: &mut R) -> io::Result<Self>;
+}
+
+macro_rules! snapshot_number {
+    ($($t:ty),*) => {
+        $(
+            impl SnapshotValue for $t {
+                fn write_value<W: Write>(&self, w: &mut W) -> io::Result<()> {
+                    w.write_all(&self.to_le_bytes())
+                }
+
+                fn read_value<R: Read>(r: &mut R) -> io::Result<$t> {
+                    let mut bytes = [0; std::mem::size_of::<$t>()];
+                    r.read_exact(&mut bytes)?;
+                    Ok(<$t>::from_le_bytes(bytes))
+                }
+            }
+        )*
+    };
+}
+
+snapshot_number!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, f32, f64);
+
+impl SnapshotValue for usize {
+    fn write_value<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        (*self as u64).write_value(w)
+    }
+
+    fn read_value<R: Read>(r: &mut R) -> io::Result<usize> {
+        usize::try_from(u64::read_value(r)?).map_err(|_| invalid("usize out of range"))
+    }
+}
+
+impl SnapshotValue for isize {
+    fn write_value<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        (*self as i64).write_value(w)
+    }
+
+    fn read_value<R: Read>(r: &mut R) -> io::Result<isize> {
+        isize::try_from(i64::read_value(r)?).map_err(|_| invalid("isize out of range"))
+    }
+}
+
+impl SnapshotValue for bool {
+    fn write_value<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        (*self as u8).write_value(w)
+    }
+
+    fn read_value<R: Read>(r: &mut R) -> io::Result<bool> {
+        match u8::read_value(r)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid("bool is neither 0 nor 1")),
+        }
+    }
+}
+
+impl SnapshotValue for char {
+    fn write_value<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        (*self as u32).write_value(w)
+    }
+
+    fn read_value<R: Read>(r: &mut R) -> io::Result<char> {
+        char::from_u32(u32::read_value(r)?).ok_or_else(|| invalid("char is not a unicode scalar value"))
+    }
+}
+
+// Strings are their length in bytes followed by the UTF-8 bytes
+impl SnapshotValue for String {
+    fn write_value<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.len().write_value(w)?;
+        w.write_all(self.as_bytes())
+    }
+
+    fn read_value<R: Read>(r: &mut R) -> io::Result<String> {
+        let len = u64::read_value(r)?;
+        // The length comes from the file, so read up to it instead of allocating it up front
+        let mut bytes = Vec::new();
+        r.take(len).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 != len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        String::from_utf8(bytes).map_err(|_| invalid("string is not valid UTF-8"))
+    }
+}
+
+// Error for a snapshot that was read fine but holds something it shouldn't
+pub(crate) fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// Indices are written as u64 with u64::MAX standing in for None
+pub(crate) fn write_index<W: Write>(w: &mut W, index: Option<usize>) -> io::Result<()> {
+    match index {
+        Some(i) => i.write_value(w),
+        None => u64::MAX.write_value(w),
+    }
+}
+
+// Reads an index written by write_index, rejecting anything that is not below slots
+pub(crate) fn read_index<R: Read>(r: &mut R, slots: usize) -> io::Result<Option<usize>> {
+    match u64::read_value(r)? {
+        u64::MAX => Ok(None),
+        i if i < slots as u64 => Ok(Some(i as usize)),
+        _ => Err(invalid("index out of range")),
+    }
+}